@@ -0,0 +1,31 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+/// Compresses the item name table so the binary doesn't carry the whole CSV uncompressed.
+///
+/// Without the CSV an empty file is written instead, so the crate still builds and items are
+/// only known by id, unless their names are looked up from battle.net.
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=src/itemsparse.csv");
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let output = out.join("itemsparse.csv.gz");
+    let mut input = match File::open("src/itemsparse.csv") {
+        Ok(input) => input,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("cargo:warning=src/itemsparse.csv is missing, building without item names");
+            File::create(output)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut encoder = GzEncoder::new(File::create(output)?, Compression::best());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    Ok(())
+}
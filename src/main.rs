@@ -1,48 +1,697 @@
-use anyhow::{Context, Result};
+mod output;
+mod rate_limit;
+mod report;
+mod retry;
+
+use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand};
 use figment::{
-    providers::{Env, Format, Toml},
+    providers::{Env, Format, Json, Serialized, Toml, Yaml},
     Figment,
 };
-use futures::stream;
-use influxdb2::models::DataPoint;
-use oauth2::basic::BasicClient;
+use flate2::read::GzDecoder;
+use influxdb2::models::{DataPoint, DataPointBuilder};
+use oauth2::basic::{BasicClient, BasicErrorResponse, BasicTokenResponse};
 use oauth2::http::HeaderValue;
-use oauth2::reqwest::async_http_client;
-use oauth2::{AccessToken, AuthUrl, ClientId, ClientSecret, TokenResponse, TokenUrl};
-use reqwest::header;
+use oauth2::{
+    AccessToken, AuthUrl, ClientId, ClientSecret, HttpRequest, HttpResponse, RefreshToken,
+    RequestTokenError, TokenResponse, TokenUrl,
+};
+#[cfg(feature = "kafka")]
+use output::KafkaSink;
+use output::{OpenMetricsFile, Output, PointWriter};
+use rate_limit::RateLimiter;
+use report::{AuctionHouseReport, RunReport, Status};
 use reqwest::ClientBuilder;
+use reqwest::{header, StatusCode};
+use retry::Backoff;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A starter config with every setting, printed by `dump-schema`.
+const EXAMPLE_CONFIG: &str = include_str!("example-config.toml");
+
+/// itemsparse.csv, gzipped by the build script. Empty if it was built without one.
+const ITEM_NAMES: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/itemsparse.csv.gz"));
+
+/// How many priced auctions `--version-check` needs before guessing whether they're in copper.
+const MIN_PRICES_FOR_UNIT_CHECK: usize = 100;
+
+/// Upper bound on how many `next` links we'll follow for a single auction house,
+/// so a misbehaving API can't keep us paging forever.
+const MAX_AUCTION_PAGES: usize = 50;
+
+/// Pause between item name lookups, so discovering a patch's worth of new items
+/// doesn't hammer the API.
+const ITEM_LOOKUP_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait before asking again for auctions that came back empty, doubling each time.
+const MAINTENANCE_RETRY_DELAY: Duration = Duration::from_secs(5);
 
-const ITEM_NAMES: &[u8] = include_bytes!("itemsparse.csv");
+const COPPER_PER_GOLD: f64 = 10_000.0;
 
+/// Shared by every battle.net API request, so connections are kept warm between them.
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Shared by every battle.net request in the process, see [`throttle`].
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Tags this tool sets itself, which config can't override. This is the canonical tag schema
+/// of item points: always `item_id`, `realm_id` and `ah_id`, plus whichever of `item_name`,
+/// `realm_name`, `datacenter`, `special`, `rand` and `seed` apply, plus the item's `itemtags`
+/// and `extratags`. Points keep their tags sorted by key, so that's the order they're written
+/// in whatever order they're added. Each optional tag only depends on its setting or the item
+/// itself, so an item's series on an auction house stays the same from run to run, except that
+/// `item_name` is added once `itemcache` looks up a new item's name. `realm_slug` is only given
+/// to `realm_info` points and `auction_id` only to `raw_auctions` points.
+const BUILTIN_TAGS: &[&str] = &[
+    "item_id",
+    "realm_id",
+    "ah_id",
+    "item_name",
+    "realm_name",
+    "datacenter",
+    "special",
+    "rand",
+    "seed",
+    "realm_slug",
+    "auction_id",
+];
+
+/// Config, from the file and `AH_` environment variables. Tests refuse unknown keys here and
+/// in every section, so the example config can't drift from these.
 #[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
 struct Settings {
     influxdb: InfluxdbSettings,
     #[serde(rename = "battlenet")]
     battle_net: BlizzardSettings,
     #[serde(rename = "auctionhouses", default)]
-    auction_houses: Vec<(i64, i64)>,
+    auction_houses: Vec<AuctionHouseEntry>,
+    /// Track auction houses found from battle.net, on top of `auctionhouses`.
+    #[serde(default)]
+    discover: Option<DiscoverSettings>,
+    /// Columns of the built-in item list to take names from, in order of preference.
+    #[serde(rename = "namecolumns", default = "default_name_columns")]
+    name_columns: Vec<String>,
+    #[serde(rename = "itemcache", default)]
+    item_cache: Option<ItemCacheSettings>,
+    /// File to remember each auction house's latest snapshot in, so unchanged ones are skipped.
+    #[serde(rename = "statefile", default)]
+    state_file: Option<PathBuf>,
+    /// File to remember every item id ever seen in, so that items seen for the first time can
+    /// be written to `new_items`. Left alone by runs that don't write points to InfluxDB.
+    #[serde(rename = "seenitems", default)]
+    seen_items: Option<PathBuf>,
+    /// Skip auction houses that keep failing for a while, remembered in `statefile`.
+    #[serde(default)]
+    quarantine: Option<QuarantineSettings>,
+    /// Named sets of items that also get written as a combined `group_summary` point.
+    #[serde(default)]
+    groups: Vec<ItemGroup>,
+    /// Ignore this percentage of the cheapest auctions when working out `min_buyout`,
+    /// so a handful of absurdly cheap listings don't drag it down.
+    #[serde(rename = "mintrimpercent", default)]
+    min_trim_percent: Option<f64>,
+    /// Leave auctions cheaper than this many copper each out of every price statistic, as
+    /// junk listings rather than real offers.
+    #[serde(rename = "minpricecopper", default)]
+    min_price_copper: Option<i64>,
+    /// Still include auctions under `minpricecopper` in `count` and `total_items`.
+    #[serde(rename = "countbelowminprice", default)]
+    count_below_min_price: bool,
+    /// Write `likely_wall_count`: how many sets of at least this many auctions of an item share
+    /// the exact same buyout and quantity. The API doesn't say who posted what, so this is only
+    /// a guess that one seller is walling the market, not proof.
+    #[serde(rename = "wallsize", default)]
+    wall_size: Option<i64>,
+    /// Boundaries in copper, lowest first, to count each item's auctions between by their price
+    /// per unit, as `price_bucket_<from>_<to>` fields. Nothing is counted when empty.
+    #[serde(rename = "pricebuckets", default)]
+    price_buckets: Vec<i64>,
+    /// Write a `competitiveness` score for each item, combining these weighted signals.
+    #[serde(default)]
+    competitiveness: Option<CompetitivenessSettings>,
+    /// Check each realm's status first and skip auction houses on realms that are down.
+    #[serde(rename = "skipoffline", default)]
+    skip_offline_realms: bool,
+    /// After every auction house is updated, also write each item's statistics across all of
+    /// them combined, tagged `realm_id=all` and `ah_id=all`. Only for InfluxDB output, and only
+    /// written when every tracked auction house had a new snapshot.
+    #[serde(rename = "allrealms", default)]
+    all_realms: bool,
+    /// After every auction house is updated, also write each item's statistics across all of
+    /// a connected realm's auction houses combined, tagged `ah_id=combined`. Only written when
+    /// each of them had a new snapshot, and only for InfluxDB output.
+    #[serde(rename = "combinefactions", default)]
+    combine_factions: bool,
+    /// What to do when a snapshot lists the same auction more than once. Only the first is
+    /// ever counted.
+    #[serde(rename = "duplicateauctions", default)]
+    duplicate_auctions: DuplicateAuctions,
+    /// The data center each connected realm is hosted in, keyed by connected realm id, written
+    /// as a `datacenter` tag. Battle.net doesn't say, so it can only come from here.
+    #[serde(default)]
+    datacenters: BTreeMap<String, String>,
+    /// Tags to add to particular items' points, such as `category = "flask"`, keyed by item id.
+    #[serde(rename = "itemtags", default)]
+    item_tags: BTreeMap<String, BTreeMap<String, String>>,
+    /// Once per run, write each tracked connected realm's member realms to `realm_info`, with
+    /// their names, region, timezone and population. Only for InfluxDB output.
+    #[serde(rename = "realminfo", default)]
+    realm_info: bool,
+    /// Tag each item's points with its connected realm's name, picked as configured when it's
+    /// made of several realms.
+    #[serde(rename = "realmname", default)]
+    realm_name: Option<RealmName>,
+    /// Items with their own market dynamics, such as the WoW Token, whose points get a
+    /// `special=true` tag so they can be picked out.
+    #[serde(rename = "specialitems", default)]
+    special_items: Vec<i64>,
+    /// Only write these items, leaving every other item out. Every item is written when empty.
+    #[serde(rename = "includeitems", default)]
+    include_items: HashSet<i64>,
+    /// File of item ids to add to `includeitems`, one per line. Anything after a comma on a
+    /// line is ignored, so a CSV of ids and names works too.
+    #[serde(rename = "itemsfile", default)]
+    items_file: Option<PathBuf>,
+    /// Roughly how many minutes each `time_left` value means, such as `SHORT = 30`. When set,
+    /// `avg_time_left_minutes` is written for each item. Unlisted values are left out of it.
+    #[serde(rename = "timeleftminutes", default)]
+    time_left_minutes: Option<BTreeMap<String, f64>>,
+    /// Webhook, such as a Discord one, to post to when an item's price crosses an alert.
+    #[serde(rename = "alertwebhook", default)]
+    alert_webhook: Option<String>,
+    /// Items to watch the `min_buyout` of, checked against the previous snapshot of the same
+    /// auction house as kept in `statefile`.
+    #[serde(default)]
+    alerts: Vec<PriceAlert>,
+    #[cfg(feature = "sentry")]
+    #[serde(default)]
+    sentry: Option<SentrySettings>,
+    #[cfg(feature = "kafka")]
+    #[serde(default)]
+    kafka: Option<KafkaSettings>,
+}
+
+impl Settings {
+    /// Minutes configured for an auction's `time_left`, if any.
+    fn time_left_minutes(&self, time_left: &str) -> Option<f64> {
+        self.time_left_minutes.as_ref()?.get(time_left).copied()
+    }
+
+    /// The configured data center of a connected realm, if any.
+    fn datacenter(&self, realm: impl Display) -> Option<&str> {
+        self.datacenters.get(&realm.to_string()).map(String::as_str)
+    }
+
+    /// The configured tags of an item, if any.
+    fn item_tags(&self, item: i64) -> Option<&BTreeMap<String, String>> {
+        self.item_tags.get(&item.to_string())
+    }
 }
 
 #[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
 struct InfluxdbSettings {
     host: String,
     org: String,
     token: AccessToken,
     bucket: String,
+    /// How many finished auction houses may queue up for a background writer.
+    /// When unset, each auction house is written before the next is fetched.
+    #[serde(rename = "writequeue", default)]
+    write_queue: Option<usize>,
+    /// Send points as line protocol to QuestDB's ILP port at this `host:port` instead of to
+    /// InfluxDB. Everything else in this section still applies to the points themselves.
+    #[serde(default)]
+    questdb: Option<String>,
+    /// Directory to save points in when they can't be written, to be sent later with `replay`.
+    #[serde(rename = "deadletter", default)]
+    dead_letter: Option<PathBuf>,
+    /// Seconds to wait for a write to InfluxDB. Separate from battle.net requests, as a big
+    /// batch can legitimately take a while.
+    #[serde(rename = "writetimeout", default)]
+    write_timeout: Option<u64>,
+    /// Seconds to wait for InfluxDB to report itself healthy before the first write, for when
+    /// it's started at the same time. Later outages are left to `deadletter` instead.
+    #[serde(rename = "startupwait", default)]
+    startup_wait: Option<u64>,
+    /// Compress writes with gzip, trading a little CPU for much less upload bandwidth.
+    #[serde(default)]
+    gzip: bool,
+    /// PEM file of CA certificates to trust for InfluxDB, alongside the system's own, such
+    /// as for a private CA.
+    #[serde(rename = "cacert", default)]
+    ca_cert: Option<PathBuf>,
+    /// PEM certificate to identify ourselves to InfluxDB with, for mutual TLS.
+    #[serde(rename = "clientcert", default)]
+    client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key for `clientcert`.
+    #[serde(rename = "clientkey", default)]
+    client_key: Option<PathBuf>,
+    /// Static tags added to every point, such as `environment = "prod"`.
+    #[serde(rename = "extratags", default)]
+    extra_tags: BTreeMap<String, String>,
+    /// Split items by their random suffix, tagging each variant with its `rand` and `seed`.
+    /// Gear with random suffixes can have dozens of variants each, so this multiplies series.
+    #[serde(rename = "randomsuffixtags", default)]
+    random_suffix_tags: bool,
+    /// Which fields to write for each item. Every field is written when unset.
+    #[serde(default)]
+    fields: Option<Vec<Field>>,
+    /// Write the lowest and highest auction id seen in each snapshot to `realm_summary`,
+    /// as a rough measure of how many new auctions were posted.
+    #[serde(rename = "auctionidrange", default)]
+    auction_id_range: bool,
+    /// Write how long each snapshot took to parse to `realm_summary`, as `parse_seconds`.
+    #[serde(rename = "parsetime", default)]
+    parse_time: bool,
+    /// Write when each snapshot was taken to `realm_summary`, as `last_snapshot` in seconds
+    /// since the unix epoch, to alert on auction houses that stop updating.
+    #[serde(rename = "lastsnapshot", default)]
+    last_snapshot: bool,
+    /// Alongside each copper price field, also write a `_gold` field with the price in gold.
+    #[serde(rename = "priceingold", default)]
+    price_in_gold: bool,
+    /// Decimal places to round `_gold` fields to, such as 2 for whole silver. The copper field
+    /// alongside them is left exact.
+    #[serde(rename = "goldprecision", default)]
+    gold_precision: Option<u32>,
+    /// Alongside each copper price field, also write the price split into whole gold, silver
+    /// and copper as `_g`, `_s` and `_c` integer fields, as the game displays it. Can't be
+    /// combined with `priceingold`.
+    #[serde(rename = "pricedenominations", default)]
+    price_denominations: bool,
+    /// Whether copper price fields are written as floats or rounded to integers. InfluxDB
+    /// rejects writes that change a field's type, so switching needs a new bucket or measurement.
+    #[serde(rename = "pricetype", default)]
+    price_type: PriceType,
+    /// Measurements to write particular fields of each item to instead of `auctions`, such as
+    /// prices to one and volumes to another. Each gets its own point with the same tags.
+    #[serde(default)]
+    measurements: BTreeMap<Field, String>,
+    /// How each measurement's points are timestamped, keyed by measurement name. Unlisted
+    /// measurements are stamped with when their data was fetched.
+    #[serde(default)]
+    timestamps: BTreeMap<String, TimestampStrategy>,
+}
+
+impl InfluxdbSettings {
+    fn writes(&self, field: Field) -> bool {
+        self.fields
+            .as_ref()
+            .is_none_or(|fields| fields.contains(&field))
+    }
+
+    /// The timestamp to give a point on `measurement`.
+    fn timestamp(&self, measurement: &str, stamps: Timestamps) -> i64 {
+        match self
+            .timestamps
+            .get(measurement)
+            .copied()
+            .unwrap_or_default()
+        {
+            TimestampStrategy::Snapshot => stamps.snapshot,
+            TimestampStrategy::Now => stamps.now,
+            TimestampStrategy::FixedHour => stamps.now - stamps.now.rem_euclid(NANOS_PER_HOUR),
+        }
+    }
+
+    /// The measurement an item's field is written to.
+    fn measurement(&self, field: Field) -> &str {
+        self.measurements
+            .get(&field)
+            .map_or("auctions", String::as_str)
+    }
+}
+
+/// Which time a measurement's points are stamped with.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum TimestampStrategy {
+    /// When battle.net last updated the snapshot, falling back to when it was fetched.
+    Snapshot,
+    /// When the data was fetched.
+    #[default]
+    Now,
+    /// When the data was fetched, rounded down to the hour, so slow-moving series line up.
+    FixedHour,
+}
+
+/// How copper prices are written.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum PriceType {
+    /// Exactly, including fractions of a copper from dividing a stack's buyout.
+    #[default]
+    Float,
+    /// Rounded to the nearest copper.
+    Integer,
+}
+
+/// A field that can be written on each item's point.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Count,
+    TotalItems,
+    MinBuyout,
+    MaxBuyout,
+    AvgBidToBuyoutRatio,
+    LikelyWallCount,
+    AvgTimeLeftMinutes,
+    UndercutGap,
+    PriceBuckets,
+    Competitiveness,
 }
 
 #[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
 struct BlizzardSettings {
     region: String,
     #[serde(rename = "clientid")]
     client_id: ClientId,
     #[serde(rename = "clientsecret")]
     client_secret: ClientSecret,
+    /// The most API requests to make per second. Blizzard allows 100.
+    #[serde(rename = "ratelimit", default)]
+    rate_limit: Option<f64>,
+    /// The most API requests to make in any hour. Blizzard allows 36,000.
+    #[serde(rename = "ratelimithourly", default)]
+    rate_limit_hourly: Option<usize>,
+    /// Proxy to send every battle.net request through. Without this, the usual
+    /// `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY` environment variables are respected.
+    #[serde(default)]
+    proxy: Option<String>,
+    /// Talk HTTP/2 straight away rather than negotiating it.
+    #[serde(default)]
+    http2: bool,
+    /// Seconds an idle connection is kept open for reuse.
+    #[serde(rename = "poolidletimeout", default)]
+    pool_idle_timeout: Option<u64>,
+    /// The most idle connections to keep open to each host.
+    #[serde(rename = "poolmaxidle", default)]
+    pool_max_idle: Option<usize>,
+    /// How many times to retry getting an access token after a failure that might not last,
+    /// and to ask again for an auction house that came back empty or cut off during
+    /// maintenance. Other requests aren't retried.
+    #[serde(default = "default_retries")]
+    retries: u32,
+    /// The most seconds to wait between retries, however many there have been.
+    #[serde(rename = "maxretrydelay", default)]
+    max_retry_delay: Option<u64>,
+    /// File holding a refresh token to authenticate with instead of client credentials, such as
+    /// for account-scoped endpoints. Battle.net may rotate it, so it's written back each time.
+    #[serde(rename = "refreshtokenfile", default)]
+    refresh_token_file: Option<PathBuf>,
+    /// API hosts to use instead of the usual ones, keyed by region, for when Blizzard moves one.
+    #[serde(rename = "apihosts", default)]
+    api_hosts: BTreeMap<String, String>,
+    /// Host to get access tokens from, instead of the usual one for the region.
+    #[serde(rename = "oauthhost", default)]
+    oauth_host: Option<String>,
+}
+
+impl BlizzardSettings {
+    /// Where the game data API for our region lives, such as `https://us.api.blizzard.com`.
+    /// China has its own host rather than a subdomain.
+    fn api_base(&self) -> String {
+        match (self.api_hosts.get(&self.region), self.region.as_str()) {
+            (Some(host), _) => format!("https://{}", host),
+            (None, "cn") => "https://gateway.battlenet.com.cn".to_string(),
+            (None, region) => format!("https://{}.api.blizzard.com", region),
+        }
+    }
+
+    fn token_url(&self) -> String {
+        let host = match (&self.oauth_host, self.region.as_str()) {
+            (Some(host), _) => host.as_str(),
+            (None, "cn") => "oauth.battlenet.com.cn",
+            (None, _) => "oauth.battle.net",
+        };
+        format!("https://{}/token", host)
+    }
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// An auction house to track, either as a `[realm, ah]` pair or as
+/// `{ realm = 4728, types = ["horde", "neutral"] }`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuctionHouseEntry {
+    Pair(RealmRef, i64),
+    Typed {
+        realm: RealmRef,
+        types: Vec<AuctionHouseType>,
+    },
+}
+
+impl AuctionHouseEntry {
+    fn realm(&self) -> &RealmRef {
+        match self {
+            AuctionHouseEntry::Pair(realm, _) => realm,
+            AuctionHouseEntry::Typed { realm, .. } => realm,
+        }
+    }
+
+    /// Every auction house this entry refers to, once its realm has been resolved to an id.
+    fn pairs(&self, realm: i64) -> Vec<(i64, i64)> {
+        match self {
+            AuctionHouseEntry::Pair(_, ah) => vec![(realm, *ah)],
+            AuctionHouseEntry::Typed { types, .. } => {
+                types.iter().map(|kind| (realm, kind.id())).collect()
+            }
+        }
+    }
+}
+
+/// A connected realm, by id or by the slug of any realm in it (such as `faerlina`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum RealmRef {
+    Id(i64),
+    Slug(String),
+}
+
+/// How loudly to complain about auctions that appear more than once in a snapshot.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateAuctions {
+    /// Drop them without saying anything.
+    #[default]
+    Ignore,
+    /// Drop them, and say how many there were.
+    Warn,
+    /// Fail the auction house, in case the rest of the snapshot can't be trusted either.
+    Error,
+}
+
+/// The auction houses that every classic connected realm has.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum AuctionHouseType {
+    Alliance,
+    Horde,
+    Neutral,
+}
+
+impl AuctionHouseType {
+    fn id(self) -> i64 {
+        match self {
+            AuctionHouseType::Alliance => 2,
+            AuctionHouseType::Horde => 6,
+            AuctionHouseType::Neutral => 7,
+        }
+    }
+}
+
+/// Which name to give a connected realm made of several realms, such as Faerlina and
+/// Heartseeker. Battle.net lists them in no particular order, so they're sorted by name first.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RealmName {
+    /// The first name alphabetically.
+    First,
+    /// Every name, separated by commas.
+    Joined,
+}
+
+impl RealmName {
+    fn pick(self, realms: &[Realm]) -> String {
+        let mut names = realms
+            .iter()
+            .map(|realm| realm.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        match self {
+            RealmName::First => names.first().copied().unwrap_or_default().to_string(),
+            RealmName::Joined => names.join(", "),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct DiscoverSettings {
+    /// Connected realms to track auction houses on. Every connected realm is used when empty.
+    #[serde(default)]
+    realms: Vec<RealmRef>,
+    /// Only track auction houses whose name contains one of these, ignoring case,
+    /// such as `["Neutral"]`. Every auction house is tracked when empty.
+    #[serde(rename = "auctionhouses", default)]
+    auction_houses: Vec<String>,
+}
+
+impl DiscoverSettings {
+    fn matches(&self, auction_house: &AuctionHouse) -> bool {
+        let name = auction_house.name.to_lowercase();
+        self.auction_houses.is_empty()
+            || self
+                .auction_houses
+                .iter()
+                .any(|filter| name.contains(&filter.to_lowercase()))
+    }
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct ItemGroup {
+    name: String,
+    items: Vec<i64>,
+}
+
+/// Posts to `alertwebhook` when an item's `min_buyout` crosses `price` copper.
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct PriceAlert {
+    item: i64,
+    direction: AlertDirection,
+    price: f64,
+}
+
+impl PriceAlert {
+    /// Whether the price went past this alert's threshold between two snapshots.
+    fn crossed(&self, before: f64, now: f64) -> bool {
+        match self.direction {
+            AlertDirection::Below => before >= self.price && now < self.price,
+            AlertDirection::Above => before <= self.price && now > self.price,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum AlertDirection {
+    Below,
+    Above,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct ItemCacheSettings {
+    /// JSON file that names looked up from battle.net are kept in between runs.
+    path: PathBuf,
+    /// The most item lookups to make against battle.net in a single run.
+    #[serde(rename = "maxlookups", default = "default_max_lookups")]
+    max_lookups: usize,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct QuarantineSettings {
+    /// How many runs in a row an auction house can fail before it's skipped.
+    #[serde(default = "default_quarantine_failures")]
+    failures: u32,
+    /// The most runs in a row to skip an auction house for. It's skipped for one run at first,
+    /// and each failure after that doubles it, up to this.
+    #[serde(rename = "maxruns", default = "default_quarantine_max_runs")]
+    max_runs: u32,
+}
+
+impl QuarantineSettings {
+    /// How many runs to skip an auction house for after this many failures in a row, if any.
+    fn runs_to_skip(&self, failures: u32) -> Option<u32> {
+        let extra = failures.checked_sub(self.failures)?;
+        Some(
+            1u32.checked_shl(extra)
+                .unwrap_or(u32::MAX)
+                .min(self.max_runs),
+        )
+    }
+}
+
+/// How much each signal counts towards an item's `competitiveness`. Each signal is between 0
+/// and 1, higher meaning more crowded, and the score is their weighted average:
+///
+/// - `listings`: `count / (count + listingsscale)`, so half at `listingsscale` auctions.
+/// - `undercutgap`: `1 - undercut_gap / min_buyout`, as a small gap means sellers are
+///   undercutting each other closely. 0 when there's only one price.
+/// - `spread`: `min_buyout / median price`, as a median close to the floor means listings
+///   are bunched around it.
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct CompetitivenessSettings {
+    #[serde(default = "default_competitiveness_weight")]
+    listings: f64,
+    #[serde(rename = "undercutgap", default = "default_competitiveness_weight")]
+    undercut_gap: f64,
+    #[serde(default = "default_competitiveness_weight")]
+    spread: f64,
+    /// How many auctions make the `listings` signal 0.5.
+    #[serde(rename = "listingsscale", default = "default_listings_scale")]
+    listings_scale: f64,
+}
+
+fn default_competitiveness_weight() -> f64 {
+    1.0
+}
+
+fn default_listings_scale() -> f64 {
+    20.0
+}
+
+fn default_quarantine_failures() -> u32 {
+    3
+}
+
+fn default_quarantine_max_runs() -> u32 {
+    32
+}
+
+fn default_name_columns() -> Vec<String> {
+    vec!["Display_lang".to_string()]
+}
+
+fn default_max_lookups() -> usize {
+    100
+}
+
+#[cfg(feature = "sentry")]
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct SentrySettings {
+    dsn: String,
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Deserialize)]
+#[cfg_attr(test, serde(deny_unknown_fields))]
+struct KafkaSettings {
+    /// Comma separated `host:port` list of brokers to bootstrap from.
+    brokers: String,
+    topic: String,
 }
 
 #[derive(Parser, Debug)]
@@ -52,211 +701,3456 @@ struct Args {
     #[arg(short, long)]
     config: Option<PathBuf>,
 
+    /// Format of the config file. Guessed from its extension if not given.
+    #[arg(long, value_enum)]
+    config_format: Option<ConfigFormat>,
+
+    /// File of item ids to only write, as `itemsfile` in the config.
+    #[arg(long)]
+    items_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Update all the prices once and then quit
-    Update,
+    Update(UpdateArgs),
 
     /// List every available auction house and its realm
-    ListAuctionHouses,
+    ListAuctionHouses {
+        /// Only list the auction houses of this connected realm id, which takes one request
+        /// rather than walking every realm
+        #[arg(long)]
+        realm: Option<i64>,
+    },
+
+    /// List the configured auction houses along with their realm and auction house names
+    ListTracked,
+
+    /// Print every auction for one item, and what would be written for it, without writing
+    InspectItem {
+        /// Connected realm id
+        realm: i64,
+
+        /// Auction house id
+        ah: i64,
+
+        /// Item id to show auctions for
+        #[arg(long)]
+        item: i64,
+    },
+
+    /// Compare an auction house now against a saved raw snapshot, and print the items that
+    /// moved the most, without writing
+    Diff {
+        /// Connected realm id
+        realm: i64,
+
+        /// Auction house id
+        ah: i64,
+
+        /// Raw snapshot to compare against
+        snapshot: PathBuf,
+
+        /// How many items to list for each kind of change
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Keep fetching one auction house and print a table of some items' min_buyout and count,
+    /// and how they changed since the last fetch, until stopped with Ctrl-C. Doesn't write
+    Watch {
+        /// Connected realm id
+        realm: i64,
+
+        /// Auction house id
+        ah: i64,
+
+        /// Item id to watch. Can be given more than once
+        #[arg(long = "item", required = true)]
+        items: Vec<i64>,
+
+        /// Seconds between fetches. Battle.net only makes a new snapshot about once an hour,
+        /// and an unchanged one isn't downloaded again
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+    },
+
+    /// Summarise how much the config tracks and how many requests each update makes, from the
+    /// config alone without asking battle.net
+    Stats,
+
+    /// Report how many series each tracked auction house adds, and which items come in the
+    /// most random suffix variants, without writing
+    PruneCardinality {
+        /// How many items with the most variants to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Print an example config with every setting, to use as a starting point
+    DumpSchema,
+
+    /// Write the built-in item list's id to name table, named with `namecolumns`, to a file
+    /// or to stdout if it's -
+    DumpItems {
+        /// Where to write the items
+        path: PathBuf,
+
+        /// Format to write in. Guessed from the path's extension if not given, else CSV
+        #[arg(long, value_enum)]
+        format: Option<ItemsFormat>,
+    },
+
+    /// Check the battle.net credentials and the InfluxDB token on their own, to tell which one
+    /// is wrong
+    VerifyToken {
+        /// Only check this one. Both are checked when not given
+        #[arg(value_enum)]
+        service: Option<TokenService>,
+    },
+
+    /// Write a minimal working config to the --config path (config.toml by default), taking
+    /// credentials from flags or asking for them, then check it by authenticating
+    Init(InitArgs),
+
+    /// Write points saved in a dead letter directory to InfluxDB, or QuestDB if that's
+    /// configured, deleting them once written
+    Replay {
+        /// Directory that failed writes were saved to
+        dir: PathBuf,
+    },
+
+    /// Aggregate raw snapshots saved by `update --save-raw` again with the current settings,
+    /// and write the result to InfluxDB
+    ReplayRaw {
+        /// Directory of raw auction snapshots
+        dir: PathBuf,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args: Args = Args::parse();
-    let settings = get_settings(&args).context("Couldn't parse settings")?;
+#[derive(clap::Args, Debug)]
+struct UpdateArgs {
+    /// Build every point as usual, but discard them instead of writing to InfluxDB. The state
+    /// file is left as it was
+    #[arg(long)]
+    output_null: bool,
 
-    let access_token = get_access_token(&settings.battle_net)
-        .await
-        .context("Couldn't authenticate with battle.net")?;
+    /// Where to send the aggregated data: influxdb, ndjson, kafka, or openmetrics://<path>
+    #[arg(long, default_value = "influxdb")]
+    output: OutputFormat,
 
-    match &args.command {
-        Command::Update => {
-            perform_single_update(&settings, access_token).await?;
-        }
-        Command::ListAuctionHouses => {
-            list_all_auction_houses(&settings, access_token).await?;
-        }
-    }
+    /// Keep going when an auction house fails, only failing if every one of them does
+    #[arg(long)]
+    continue_on_error: bool,
 
-    Ok(())
+    /// Stop at the first auction or snapshot that doesn't look as expected, printing it. For
+    /// checking the API after a new expansion or patch, not for everyday use
+    #[arg(long, conflicts_with = "continue_on_error")]
+    fail_fast: bool,
+
+    /// Warn if the first snapshot fetched doesn't look like what the API used to return
+    #[arg(long)]
+    version_check: bool,
+
+    /// Check each realm's auction house index first, and only download auctions when it's
+    /// changed since last time. Needs `statefile` to be set
+    #[arg(long)]
+    only_changed_realms: bool,
+
+    /// Only write this many items with the most auctions on each auction house, as a guard
+    /// against absurd snapshots
+    #[arg(long)]
+    max_items: Option<usize>,
+
+    /// Don't read or look up item names, leaving the item_name tag off every point
+    #[arg(long)]
+    no_names: bool,
+
+    /// Write a JSON report of what happened to each auction house to this file, or to stdout
+    /// if it's -
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Also save each auction house's auctions as fetched to this directory, named
+    /// <realm>-<ah>-<timestamp>.json, for replay-raw and diff
+    #[arg(long)]
+    save_raw: Option<PathBuf>,
+
+    /// Warn about points written in the same run that share a measurement, tags and timestamp,
+    /// which InfluxDB would quietly merge. For debugging config, such as a field moved onto
+    /// new_items by [influxdb.measurements] when both are stamped the same way
+    #[arg(long)]
+    check_duplicate_points: bool,
+
+    /// Write every auction as its own point on raw_auctions, instead of per-item statistics.
+    /// That's a series per auction, so only for short, high resolution analysis
+    #[arg(long)]
+    raw_points: bool,
+
+    /// Only write each auction house's realm_summary, with its count of auctions, how many
+    /// different items there were and their total_buyout, leaving out every per-item point.
+    /// Also leaves out groups, allrealms, combinefactions and new_items
+    #[arg(long, conflicts_with = "raw_points")]
+    summary_only: bool,
 }
 
-async fn perform_single_update(settings: &Settings, access_token: HeaderValue) -> Result<()> {
-    let client = influxdb2::Client::new(
-        &settings.influxdb.host,
-        &settings.influxdb.org,
-        settings.influxdb.token.secret(),
-    );
-    let names_by_id = read_names_by_id();
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TokenService {
+    Battlenet,
+    Influxdb,
+}
 
-    for (realm, ah) in &settings.auction_houses {
-        update_prices(
-            &settings,
-            &client,
-            &names_by_id,
-            access_token.clone(),
-            *realm,
-            *ah,
-        )
-        .await
-        .context("Couldn't update price data")?;
-    }
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ItemsFormat {
+    Csv,
+    Json,
+}
 
-    println!("Done!");
-    Ok(())
+#[derive(clap::Args, Debug)]
+struct InitArgs {
+    /// Battle.net region: us, eu, kr, tw or cn
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Battle.net API client id
+    #[arg(long)]
+    client_id: Option<String>,
+
+    /// Battle.net API client secret
+    #[arg(long)]
+    client_secret: Option<String>,
+
+    /// InfluxDB URL
+    #[arg(long)]
+    influxdb_host: Option<String>,
+
+    /// InfluxDB organization
+    #[arg(long)]
+    influxdb_org: Option<String>,
+
+    /// InfluxDB API token, with write access to the bucket
+    #[arg(long)]
+    influxdb_token: Option<String>,
+
+    /// InfluxDB bucket to write to
+    #[arg(long)]
+    influxdb_bucket: Option<String>,
+
+    /// Overwrite the config file if it already exists
+    #[arg(long)]
+    force: bool,
 }
 
-async fn list_all_auction_houses(settings: &Settings, access_token: HeaderValue) -> Result<()> {
-    for connected_realm in get_connected_realms(settings, access_token.clone())
-        .await?
-        .connected_realms
-    {
-        let connected_realm =
-            get_connected_realm(settings, access_token.clone(), connected_realm).await?;
-        for realm in connected_realm.realms {
-            println!("- {} -", realm.name);
+#[derive(Clone, Debug)]
+enum OutputFormat {
+    /// Write points to InfluxDB
+    Influxdb,
+    /// Print one JSON object per item per auction house to stdout
+    Ndjson,
+    /// Write gauges to a file in OpenMetrics text format, such as for node_exporter's
+    /// textfile collector
+    OpenMetrics(PathBuf),
+    /// Publish one JSON message per item per auction house to the configured Kafka topic
+    #[cfg(feature = "kafka")]
+    Kafka,
+}
 
-            for auction_house in
-                get_auction_houses(settings, access_token.clone(), connected_realm.id)
-                    .await?
-                    .auctions
-            {
-                println!(
-                    "{} / {} - {}",
-                    connected_realm.id, auction_house.id, auction_house.name
-                );
-            }
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "influxdb" => Ok(OutputFormat::Influxdb),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            #[cfg(feature = "kafka")]
+            "kafka" => Ok(OutputFormat::Kafka),
+            _ => match s.strip_prefix("openmetrics://") {
+                Some(path) if !path.is_empty() => Ok(OutputFormat::OpenMetrics(path.into())),
+                _ => Err(format!(
+                    "expected influxdb, ndjson, kafka (built with --features kafka) or \
+                     openmetrics://<path>, got '{}'",
+                    s
+                )),
+            },
         }
     }
-    Ok(())
 }
 
-fn get_settings(args: &Args) -> Result<Settings> {
-    let mut settings = Figment::new();
-    if let Some(path) = &args.config {
-        settings = settings.merge(Toml::file(path));
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = Args::parse();
+    if let Command::DumpSchema = args.command {
+        // New users won't have a config that parses yet.
+        print!("{}", EXAMPLE_CONFIG);
+        return Ok(());
+    }
+    if let Command::Init(init_args) = &args.command {
+        let path = args.config.as_deref().unwrap_or(Path::new("config.toml"));
+        return init_config(init_args, path).await;
     }
-    settings = settings.merge(Env::prefixed("AH_").split("_"));
-    Ok(settings.extract()?)
-}
 
-async fn update_prices(
-    settings: &Settings,
-    client: &influxdb2::Client,
-    names_by_id: &HashMap<i64, String>,
-    access_token: HeaderValue,
-    realm: i64,
-    ah: i64,
-) -> Result<()> {
-    let auctions = get_auctions(&settings, access_token, realm, ah)
-        .await
-        .context("Couldn't fetch list of auctions from battle.net")?
-        .auctions;
-    let mut by_items: HashMap<i64, ItemData> = HashMap::new();
+    let settings = get_settings(
+        args.config.as_deref(),
+        args.config_format,
+        args.items_file.as_deref(),
+    )
+    .context("Couldn't parse settings")?;
 
-    for auction in auctions {
-        let mut entry = by_items.entry(auction.item.id).or_default();
-        entry.auctions += 1;
-        entry.total_items = entry.total_items.saturating_add(auction.quantity);
-        if auction.buyout > 0 {
-            let min_buyout = auction.buyout / auction.quantity;
-            if entry.min_buyout == 0 || entry.min_buyout > min_buyout {
-                entry.min_buyout = min_buyout;
-            }
-        }
+    #[cfg(feature = "sentry")]
+    let _sentry = settings
+        .sentry
+        .as_ref()
+        .map(|sentry| sentry::init(sentry.dsn.as_str()));
+
+    let result = run(&args, &settings).await;
+
+    #[cfg(feature = "sentry")]
+    if let Err(e) = &result {
+        sentry::integrations::anyhow::capture_anyhow(e);
     }
 
-    let mut points = vec![];
-    for (id, data) in by_items {
-        let mut point = DataPoint::builder("auctions")
-            .tag("item_id", id.to_string())
-            .tag("realm_id", realm.to_string())
-            .tag("ah_id", ah.to_string())
-            .field("count", data.auctions)
-            .field("total_items", data.total_items)
-            .field("min_buyout", data.min_buyout);
+    result
+}
 
-        if let Some(name) = names_by_id.get(&id) {
-            point = point.tag("item_name", name)
+async fn run(args: &Args, settings: &Settings) -> Result<()> {
+    let writes_influxdb = match &args.command {
+        Command::Update(update_args) => {
+            matches!(update_args.output, OutputFormat::Influxdb) && !update_args.output_null
         }
-
-        points.push(point.build()?);
+        Command::Replay { .. } | Command::ReplayRaw { .. } => true,
+        _ => false,
+    };
+    if let (true, Some(seconds), None) = (
+        writes_influxdb,
+        settings.influxdb.startup_wait,
+        &settings.influxdb.questdb,
+    ) {
+        output::wait_for_influxdb(&settings.influxdb, Duration::from_secs(seconds)).await?;
     }
 
-    client
-        .write(&settings.influxdb.bucket, stream::iter(points))
-        .await?;
-
-    Ok(())
-}
+    if let Command::Replay { dir } = &args.command {
+        // Doesn't talk to battle.net, so there's no need to authenticate.
+        return PointWriter::replay(&settings.influxdb, dir).await;
+    }
+    if let Command::ReplayRaw { dir } = &args.command {
+        return replay_raw(settings, dir).await;
+    }
+    if let Command::DumpItems { path, format } = &args.command {
+        return dump_items(settings, path, *format);
+    }
+    if let Command::VerifyToken { service } = &args.command {
+        return verify_tokens(settings, *service).await;
+    }
+    if let Command::Stats = &args.command {
+        // Works from the config alone, so it can be checked before credentials are set up.
+        print_stats(settings);
+        return Ok(());
+    }
 
-fn read_names_by_id() -> HashMap<i64, String> {
-    let mut result = HashMap::new();
-    let mut reader = csv::Reader::from_reader(ITEM_NAMES);
+    let access_token = get_access_token(&settings.battle_net)
+        .await
+        .context("Couldn't authenticate with battle.net")?;
 
-    for record in reader.records() {
-        if let Ok(record) = record {
-            if let (Some(id), Some(name)) = (
-                record.get(0).and_then(|s| i64::from_str(s).ok()),
-                record.get(6),
-            ) {
-                result.insert(id, name.to_string());
+    match &args.command {
+        Command::Update(update_args) => {
+            perform_single_update(settings, update_args, access_token).await?;
+        }
+        Command::ListAuctionHouses { realm: None } => {
+            list_all_auction_houses(settings, access_token).await?;
+        }
+        Command::ListAuctionHouses { realm: Some(realm) } => {
+            list_realm_auction_houses(settings, access_token, *realm).await?;
+        }
+        Command::ListTracked => {
+            list_tracked_auction_houses(settings, access_token).await?;
+        }
+        Command::InspectItem { realm, ah, item } => {
+            inspect_item(settings, access_token, *realm, *ah, *item).await?;
+        }
+        Command::Diff {
+            realm,
+            ah,
+            snapshot,
+            top,
+        } => {
+            print_diff(settings, access_token, *realm, *ah, snapshot, *top).await?;
+        }
+        Command::Watch {
+            realm,
+            ah,
+            items,
+            interval,
+        } => {
+            let interval = Duration::from_secs(*interval);
+            tokio::select! {
+                result = watch_items(settings, access_token, *realm, *ah, items, interval) => result?,
+                result = tokio::signal::ctrl_c() => {
+                    result.context("Couldn't listen for Ctrl-C")?;
+                    println!();
+                }
             }
         }
+        Command::PruneCardinality { top } => {
+            print_cardinality(settings, access_token, *top).await?;
+        }
+        Command::DumpSchema | Command::Init(_) => unreachable!("Handled before reading settings"),
+        Command::Replay { .. }
+        | Command::ReplayRaw { .. }
+        | Command::DumpItems { .. }
+        | Command::VerifyToken { .. }
+        | Command::Stats => unreachable!("Handled before authenticating"),
     }
 
-    result
+    Ok(())
 }
 
-async fn get_auctions(
+async fn perform_single_update(
     settings: &Settings,
+    args: &UpdateArgs,
     access_token: HeaderValue,
+) -> Result<()> {
+    let mut output = match &args.output {
+        OutputFormat::Influxdb if args.output_null => {
+            Output::Points(PointWriter::Null { discarded: 0 })
+        }
+        OutputFormat::Influxdb => Output::Points(PointWriter::new(&settings.influxdb)?),
+        OutputFormat::Ndjson => Output::Ndjson,
+        OutputFormat::OpenMetrics(path) => Output::OpenMetrics(OpenMetricsFile::new(path)),
+        #[cfg(feature = "kafka")]
+        OutputFormat::Kafka => {
+            let kafka = settings
+                .kafka
+                .as_ref()
+                .ok_or_else(|| anyhow!("--output kafka needs a [kafka] section in the config"))?;
+            Output::Kafka(KafkaSink::new(&kafka.brokers, &kafka.topic)?)
+        }
+    };
+    if args.raw_points {
+        if !matches!(args.output, OutputFormat::Influxdb) {
+            return Err(anyhow!("--raw-points only works with InfluxDB output"));
+        }
+        eprintln!("**********************************************************************");
+        eprintln!("WARNING: --raw-points writes every auction as its own series.");
+        eprintln!("That's hundreds of thousands of series and points per auction house per");
+        eprintln!("update. Use a bucket with a short retention period, not long-term storage.");
+        eprintln!("**********************************************************************");
+    }
+    if args.summary_only && !matches!(args.output, OutputFormat::Influxdb) {
+        return Err(anyhow!("--summary-only only works with InfluxDB output"));
+    }
+    let mut names_by_id = if args.no_names {
+        HashMap::new()
+    } else {
+        read_names_by_id(&settings.name_columns)?
+    };
+    let mut item_cache = match &settings.item_cache {
+        Some(cache_settings) if !args.no_names => {
+            let cache = ItemNameCache::load(cache_settings)?;
+            names_by_id.extend(cache.names.clone());
+            Some(cache)
+        }
+        _ => None,
+    };
+    if !args.no_names {
+        let mut unknown = settings
+            .include_items
+            .iter()
+            .filter(|id| !names_by_id.contains_key(id))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            unknown.sort_unstable();
+            eprintln!(
+                "WARNING: these included items have no name, check they're right: {:?}",
+                unknown
+            );
+        }
+    }
+    let mut state = match &settings.state_file {
+        Some(path) => Some(StateFile::load(path)?),
+        None if args.only_changed_realms => {
+            return Err(anyhow!("--only-changed-realms needs statefile to be set"));
+        }
+        None => None,
+    };
+
+    let auction_houses = tracked_auction_houses(settings, access_token.clone()).await?;
+    let mut realm_names = HashMap::new();
+    if let Some(realm_name) = settings.realm_name {
+        for (realm, _) in &auction_houses {
+            if !realm_names.contains_key(realm) {
+                let link = connected_realm_link(settings, *realm);
+                let connected_realm = get_connected_realm(settings, access_token.clone(), link)
+                    .await
+                    .with_context(|| format!("Couldn't look up the name of realm {}", realm))?;
+                realm_names.insert(*realm, realm_name.pick(&connected_realm.realms));
+            }
+        }
+    }
+
+    let mut seen_items = match &settings.seen_items {
+        Some(path) => Some(SeenItems::load(path)?),
+        None => None,
+    };
+
+    let mut realms_online: HashMap<i64, bool> = HashMap::new();
+    let mut indexes_modified: HashMap<i64, Option<String>> = HashMap::new();
+    let mut realms_described: HashSet<i64> = HashSet::new();
+    let mut check_schema = args.version_check;
+    // The series and timestamp of every point written, for --check-duplicate-points.
+    let mut series_written = args.check_duplicate_points.then(HashSet::new);
+    // How many auction houses were updated, and their items merged.
+    let mut rollup: Option<(usize, HashMap<i64, ItemData>)> =
+        (settings.all_realms && !args.summary_only).then(Default::default);
+    // How many auction houses of each realm were updated, and their items merged.
+    let mut combined: Option<BTreeMap<i64, (usize, HashMap<i64, ItemData>)>> =
+        (settings.combine_factions && !args.summary_only).then(BTreeMap::new);
+    let mut report = RunReport::default();
+    let mut succeeded = 0;
+    let mut failed = 0;
+
+    for (realm, ah) in &auction_houses {
+        let started = Instant::now();
+        if let (Some(state), Some(_)) = (state.as_mut(), &settings.quarantine) {
+            if let Some(runs) = state.skip_quarantined(*realm, *ah) {
+                eprintln!(
+                    "Skipping realm {} AH {} as it's quarantined after failing repeatedly, \
+                     {} more runs to skip",
+                    realm, ah, runs
+                );
+                report.auction_houses.push(AuctionHouseReport::new(
+                    *realm,
+                    *ah,
+                    Status::Skipped,
+                    started,
+                ));
+                continue;
+            }
+        }
+        if settings.skip_offline_realms {
+            let online = match realms_online.get(realm) {
+                Some(online) => *online,
+                None => {
+                    let online = is_realm_online(settings, access_token.clone(), *realm).await;
+                    realms_online.insert(*realm, online);
+                    online
+                }
+            };
+            if !online {
+                eprintln!("Skipping realm {} AH {} as the realm is offline", realm, ah);
+                report.auction_houses.push(AuctionHouseReport::new(
+                    *realm,
+                    *ah,
+                    Status::Skipped,
+                    started,
+                ));
+                continue;
+            }
+        }
+
+        let index_modified = if args.only_changed_realms {
+            if !indexes_modified.contains_key(realm) {
+                let modified = get_auction_index_modified(settings, access_token.clone(), *realm)
+                    .await
+                    .with_context(|| format!("Couldn't check auction index for realm {}", realm));
+                match modified {
+                    Ok(modified) => {
+                        indexes_modified.insert(*realm, modified);
+                    }
+                    Err(e) if args.continue_on_error => {
+                        eprintln!("{:#}", e);
+                        quarantine_on_failure(settings, state.as_mut(), *realm, *ah);
+                        report
+                            .auction_houses
+                            .push(AuctionHouseReport::failed(*realm, *ah, started, &e));
+                        failed += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        quarantine_on_failure(settings, state.as_mut(), *realm, *ah);
+                        report
+                            .auction_houses
+                            .push(AuctionHouseReport::failed(*realm, *ah, started, &e));
+                        save_failed_run(args, output, state.as_ref()).await;
+                        save_report(args, &report)?;
+                        return Err(e);
+                    }
+                }
+            }
+            indexes_modified[realm].clone()
+        } else {
+            None
+        };
+        if let (Some(state), Some(modified)) = (&state, &index_modified) {
+            if state.index_modified(*realm, *ah) == Some(modified.as_str()) {
+                eprintln!(
+                    "Auction index for realm {} hasn't changed, skipping AH {}",
+                    realm, ah
+                );
+                report.auction_houses.push(AuctionHouseReport::new(
+                    *realm,
+                    *ah,
+                    Status::Unchanged,
+                    started,
+                ));
+                continue;
+            }
+        }
+
+        if let (true, Output::Points(writer)) = (settings.realm_info, &mut output) {
+            if realms_described.insert(*realm) {
+                // Only nice to have, so it's not worth failing the auction house over.
+                match build_realm_info_points(settings, access_token.clone(), *realm).await {
+                    Ok(points) => {
+                        if let Some(seen) = &mut series_written {
+                            warn_duplicate_series(seen, &points)?;
+                        }
+                        writer
+                            .write(points)
+                            .await
+                            .with_context(|| format!("Couldn't write realm info for {}", realm))?
+                    }
+                    Err(e) => eprintln!("Couldn't describe realm {}: {:#}", realm, e),
+                }
+            }
+        }
+
+        let result = update_prices(
+            &settings,
+            args,
+            &mut output,
+            &mut names_by_id,
+            item_cache.as_mut(),
+            state.as_mut(),
+            access_token.clone(),
+            *realm,
+            realm_names.get(realm).map(String::as_str),
+            *ah,
+            check_schema,
+            series_written.as_mut(),
+        )
+        .await
+        .with_context(|| format!("Couldn't update price data for realm {} AH {}", realm, ah));
+        match result {
+            Ok(update) => {
+                let updated = match update {
+                    AuctionHouseUpdate::Updated(updated) => Some(updated),
+                    AuctionHouseUpdate::Unchanged => None,
+                    // Nothing was fetched, so it's neither a success nor the latest index.
+                    AuctionHouseUpdate::Skipped => {
+                        report.auction_houses.push(AuctionHouseReport::new(
+                            *realm,
+                            *ah,
+                            Status::Skipped,
+                            started,
+                        ));
+                        continue;
+                    }
+                };
+                check_schema = false;
+                succeeded += 1;
+                report.auction_houses.push(match &updated {
+                    Some(updated) => AuctionHouseReport {
+                        auctions: Some(updated.auctions),
+                        items: Some(updated.by_items.len()),
+                        timestamp: Some(updated.snapshot),
+                        ..AuctionHouseReport::new(*realm, *ah, Status::Updated, started)
+                    },
+                    None => AuctionHouseReport::new(*realm, *ah, Status::Unchanged, started),
+                });
+                // Items are only remembered as seen when a new_items point can be written for
+                // them, or they'd never be reported.
+                let write_new_items = !args.summary_only && !args.output_null;
+                if let (true, Some(seen), Some(updated), Output::Points(writer)) =
+                    (write_new_items, &mut seen_items, &updated, &mut output)
+                {
+                    let new_items = seen.add(updated.by_items.keys().copied());
+                    if !new_items.is_empty() {
+                        let mut stamps = Timestamps::now()?;
+                        stamps.snapshot = updated.snapshot;
+                        let points = build_new_item_points(
+                            settings,
+                            &new_items,
+                            &updated.by_items,
+                            &names_by_id,
+                            *realm,
+                            *ah,
+                            stamps,
+                        )?;
+                        if let Some(seen) = &mut series_written {
+                            warn_duplicate_series(seen, &points)?;
+                        }
+                        writer.write(points).await.with_context(|| {
+                            format!("Couldn't write new items for realm {} AH {}", realm, ah)
+                        })?;
+                    }
+                }
+                if let (Some(combined), Some(updated)) = (&mut combined, &updated) {
+                    let (updated_count, items) = combined.entry(*realm).or_default();
+                    *updated_count += 1;
+                    for (id, data) in &updated.by_items {
+                        items.entry(*id).or_default().merge(data);
+                    }
+                }
+                if let (Some((updated_count, items)), Some(updated)) = (&mut rollup, updated) {
+                    *updated_count += 1;
+                    for (id, data) in &updated.by_items {
+                        items.entry(*id).or_default().merge(data);
+                    }
+                }
+                if let (Some(state), Some(modified)) = (state.as_mut(), index_modified) {
+                    state.set_index_modified(*realm, *ah, modified);
+                }
+                if let (Some(state), Some(quarantine)) = (state.as_mut(), &settings.quarantine) {
+                    if state.clear_failures(*realm, *ah, quarantine) {
+                        eprintln!("Realm {} AH {} has recovered from quarantine", realm, ah);
+                    }
+                }
+            }
+            Err(e) if args.continue_on_error => {
+                eprintln!("{:#}", e);
+                quarantine_on_failure(settings, state.as_mut(), *realm, *ah);
+                report
+                    .auction_houses
+                    .push(AuctionHouseReport::failed(*realm, *ah, started, &e));
+                failed += 1;
+            }
+            Err(e) => {
+                quarantine_on_failure(settings, state.as_mut(), *realm, *ah);
+                report
+                    .auction_houses
+                    .push(AuctionHouseReport::failed(*realm, *ah, started, &e));
+                save_failed_run(args, output, state.as_ref()).await;
+                save_report(args, &report)?;
+                return Err(e);
+            }
+        }
+    }
+
+    if let (Some(combined), Output::Points(writer)) = (&combined, &mut output) {
+        for (realm, (updated_count, items)) in combined {
+            let tracked = auction_houses.iter().filter(|(r, _)| r == realm).count();
+            if *updated_count < tracked {
+                eprintln!(
+                    "Not combining realm {}'s auction houses, as only {} of {} had a new snapshot",
+                    realm, updated_count, tracked
+                );
+                continue;
+            }
+            let stamps = Timestamps::now()?;
+            let points = build_points(
+                settings,
+                items,
+                &names_by_id,
+                realm,
+                realm_names.get(realm).map(String::as_str),
+                "combined",
+                stamps,
+            )?;
+            if let Some(seen) = &mut series_written {
+                warn_duplicate_series(seen, &points)?;
+            }
+            writer
+                .write(points)
+                .await
+                .with_context(|| format!("Couldn't write combined items for realm {}", realm))?;
+        }
+    }
+
+    if let (Some((updated_count, items)), Output::Points(writer)) = (&rollup, &mut output) {
+        if *updated_count < auction_houses.len() {
+            eprintln!(
+                "Not writing the cross-realm rollup, as only {} of {} auction houses had a new \
+                 snapshot",
+                updated_count,
+                auction_houses.len()
+            );
+        } else if !items.is_empty() {
+            let stamps = Timestamps::now()?;
+            let points = build_points(settings, items, &names_by_id, "all", None, "all", stamps)?;
+            if let Some(seen) = &mut series_written {
+                warn_duplicate_series(seen, &points)?;
+            }
+            writer
+                .write(points)
+                .await
+                .context("Couldn't write cross-realm rollup")?;
+        }
+    }
+
+    output.finish().await.context("Couldn't write price data")?;
+
+    if let Some(cache) = &item_cache {
+        cache.save().context("Couldn't save item name cache")?;
+    }
+    if let Some(seen) = &seen_items {
+        seen.save().context("Couldn't save seen items")?;
+    }
+    save_state(args, state.as_ref())?;
+    save_report(args, &report)?;
+
+    if args.continue_on_error {
+        eprintln!("Updated {} auction houses, {} failed", succeeded, failed);
+        if succeeded == 0 && failed > 0 {
+            return Err(anyhow!("Every auction house failed to update"));
+        }
+    }
+
+    eprintln!("Done!");
+    Ok(())
+}
+
+/// Counts a failed auction house towards quarantining it, when that's configured.
+fn quarantine_on_failure(settings: &Settings, state: Option<&mut StateFile>, realm: i64, ah: i64) {
+    if let (Some(state), Some(quarantine)) = (state, &settings.quarantine) {
+        if let Some(runs) = state.add_failure(realm, ah, quarantine) {
+            eprintln!(
+                "Quarantining realm {} AH {} for {} runs after failing repeatedly",
+                realm, ah, runs
+            );
+        }
+    }
+}
+
+/// Keeps what's already been done when a run is about to fail: the state file, with the
+/// failure counted towards quarantine and the ETags of every auction house updated before it.
+/// The state is only saved once anything still queued is written, and problems are only
+/// logged so as not to hide the failure itself.
+async fn save_failed_run(args: &UpdateArgs, output: Output, state: Option<&StateFile>) {
+    let result = match output.finish().await {
+        Ok(()) => save_state(args, state),
+        Err(e) => Err(e.context("Couldn't finish writing price data")),
+    };
+    if let Err(e) = result {
+        eprintln!("{:#}", e);
+    }
+}
+
+/// Saves the state file, unless the points were only thrown away by --output-null. Its ETags,
+/// indexes and alert prices would then make the next run skip snapshots never really written.
+fn save_state(args: &UpdateArgs, state: Option<&StateFile>) -> Result<()> {
+    match state {
+        Some(state) if !args.output_null => state.save().context("Couldn't save state file"),
+        _ => Ok(()),
+    }
+}
+
+fn save_report(args: &UpdateArgs, report: &RunReport) -> Result<()> {
+    match &args.report {
+        Some(path) => report.save(path).context("Couldn't write run report"),
+        None => Ok(()),
+    }
+}
+
+/// Aggregates every raw snapshot in `dir` with the current settings and writes the points,
+/// oldest first. The auction house and timestamp come from each file's name.
+async fn replay_raw(settings: &Settings, dir: &Path) -> Result<()> {
+    let names_by_id = read_names_by_id(&settings.name_columns)?;
+    let mut writer = PointWriter::new(&settings.influxdb)?;
+
+    let mut snapshots = vec![];
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("Couldn't read raw directory {:?}", dir))?
+    {
+        let path = entry?.path();
+        match parse_raw_snapshot_name(&path) {
+            Some((realm, ah, timestamp)) => snapshots.push((timestamp, realm, ah, path)),
+            None => eprintln!("Skipping {:?}, which isn't named like a raw snapshot", path),
+        }
+    }
+    snapshots.sort();
+
+    for (timestamp, realm, ah, path) in &snapshots {
+        let file = File::open(path).with_context(|| format!("Couldn't open {:?}", path))?;
+        let auction_list: AuctionList = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Couldn't parse {:?}", path))?;
+        let auctions = dedupe_auctions(settings, auction_list.auctions, *realm, *ah)
+            .with_context(|| format!("Couldn't replay {:?}", path))?;
+        let by_items = aggregate(settings, &auctions);
+
+        let points = snapshot_points(
+            settings,
+            &auctions,
+            &by_items,
+            &names_by_id,
+            *realm,
+            None,
+            *ah,
+            Timestamps {
+                snapshot: *timestamp,
+                now: *timestamp,
+            },
+            None,
+        )?;
+        writer
+            .write(points)
+            .await
+            .with_context(|| format!("Couldn't write points for {:?}", path))?;
+        eprintln!("Replayed {:?}", path);
+    }
+
+    writer.finish().await?;
+    eprintln!("Replayed {} raw snapshots", snapshots.len());
+    Ok(())
+}
+
+/// Checks each credential on its own and prints what it's good for, carrying on to the next
+/// when one fails so that it's clear which is wrong.
+async fn verify_tokens(settings: &Settings, service: Option<TokenService>) -> Result<()> {
+    let mut failed = vec![];
+
+    if service.is_none_or(|service| service == TokenService::Battlenet) {
+        match request_token(&settings.battle_net).await {
+            Ok(token) => {
+                println!("battle.net: OK, got a {:?} token", token.token_type());
+                match token.expires_in() {
+                    Some(expires_in) => {
+                        println!("battle.net: expires in {}s", expires_in.as_secs())
+                    }
+                    None => println!("battle.net: didn't say when it expires"),
+                }
+            }
+            Err(e) => {
+                println!("battle.net: FAILED: {:#}", e);
+                failed.push("battle.net");
+            }
+        }
+    }
+
+    if service.is_none_or(|service| service == TokenService::Influxdb) {
+        match output::verify_influxdb_token(&settings.influxdb).await {
+            Ok(user) => println!(
+                "InfluxDB: OK, authenticated as {} for org {}",
+                user, settings.influxdb.org
+            ),
+            Err(e) => {
+                println!("InfluxDB: FAILED: {:#}", e);
+                failed.push("InfluxDB");
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Credentials didn't work for {}",
+            failed.join(" and ")
+        ))
+    }
+}
+
+/// Writes every named item in the built-in item list, by id, as CSV or JSON.
+fn dump_items(settings: &Settings, path: &Path, format: Option<ItemsFormat>) -> Result<()> {
+    #[derive(Serialize)]
+    struct ItemName<'a> {
+        id: i64,
+        name: &'a str,
+    }
+
+    let names_by_id = read_names_by_id(&settings.name_columns)?;
+    let mut items = names_by_id
+        .iter()
+        .map(|(id, name)| ItemName { id: *id, name })
+        .collect::<Vec<_>>();
+    items.sort_unstable_by_key(|item| item.id);
+
+    let format = format.unwrap_or_else(|| match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => ItemsFormat::Json,
+        _ => ItemsFormat::Csv,
+    });
+    let out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(path).with_context(|| format!("Couldn't create {:?}", path))?)
+    };
+    let mut out = BufWriter::new(out);
+    match format {
+        ItemsFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            for item in &items {
+                writer.serialize(item)?;
+            }
+            writer.flush()?;
+        }
+        ItemsFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &items)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()
+        .with_context(|| format!("Couldn't write items to {:?}", path))?;
+
+    eprintln!("Wrote {} items", items.len());
+    Ok(())
+}
+
+/// Saves the auctions of a snapshot taken at `timestamp`, in nanoseconds since the unix epoch,
+/// with the name that `parse_raw_snapshot_name` reads back.
+fn save_raw_snapshot(
+    dir: &Path,
+    auction_list: &AuctionList,
+    realm: i64,
+    ah: i64,
+    timestamp: i64,
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Couldn't create raw directory {:?}", dir))?;
+    let path = dir.join(format!("{}-{}-{}.json", realm, ah, timestamp));
+    let file = File::create(&path).with_context(|| format!("Couldn't create {:?}", path))?;
+    serde_json::to_writer(BufWriter::new(file), auction_list)
+        .with_context(|| format!("Couldn't save raw snapshot to {:?}", path))
+}
+
+/// Reads the realm, auction house and unix timestamp in nanoseconds out of a raw snapshot's
+/// file name, such as `4728-2-1665849600000000000.json`.
+fn parse_raw_snapshot_name(path: &Path) -> Option<(i64, i64, i64)> {
+    let stem = path.file_name()?.to_str()?.strip_suffix(".json")?;
+    let mut parts = stem.splitn(3, '-');
+    let realm = parts.next()?.parse().ok()?;
+    let ah = parts.next()?.parse().ok()?;
+    let timestamp = parts.next()?.parse().ok()?;
+    Some((realm, ah, timestamp))
+}
+
+async fn list_all_auction_houses(settings: &Settings, access_token: HeaderValue) -> Result<()> {
+    for connected_realm in get_connected_realms(settings, access_token.clone())
+        .await?
+        .connected_realms
+    {
+        let connected_realm =
+            get_connected_realm(settings, access_token.clone(), connected_realm).await?;
+        println!("- {} -", RealmName::Joined.pick(&connected_realm.realms));
+
+        for auction_house in get_auction_houses(settings, access_token.clone(), connected_realm.id)
+            .await?
+            .auctions
+        {
+            println!(
+                "{} / {} - {}",
+                connected_realm.id, auction_house.id, auction_house.name
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn list_realm_auction_houses(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+) -> Result<()> {
+    for auction_house in get_auction_houses(settings, access_token, realm)
+        .await
+        .with_context(|| format!("Couldn't list auction houses of realm {}", realm))?
+        .auctions
+    {
+        println!("{} / {} - {}", realm, auction_house.id, auction_house.name);
+    }
+    Ok(())
+}
+
+/// Every (connected realm, auction house) pair to track, from config and discovery.
+async fn tracked_auction_houses(
+    settings: &Settings,
+    access_token: HeaderValue,
+) -> Result<Vec<(i64, i64)>> {
+    let mut slugs = HashMap::new();
+    let mut result = vec![];
+    for entry in &settings.auction_houses {
+        let realm =
+            resolve_realm(settings, access_token.clone(), entry.realm(), &mut slugs).await?;
+        for pair in entry.pairs(realm) {
+            if !result.contains(&pair) {
+                result.push(pair);
+            }
+        }
+    }
+
+    if let Some(discover) = &settings.discover {
+        let realms = if discover.realms.is_empty() {
+            let mut realms = vec![];
+            for link in get_connected_realms(settings, access_token.clone())
+                .await
+                .context("Couldn't discover connected realms")?
+                .connected_realms
+            {
+                realms.push(
+                    get_connected_realm(settings, access_token.clone(), link)
+                        .await?
+                        .id,
+                );
+            }
+            realms
+        } else {
+            let mut realms = vec![];
+            for realm in &discover.realms {
+                realms
+                    .push(resolve_realm(settings, access_token.clone(), realm, &mut slugs).await?);
+            }
+            realms
+        };
+
+        for realm in realms {
+            for auction_house in get_auction_houses(settings, access_token.clone(), realm)
+                .await
+                .with_context(|| format!("Couldn't discover auction houses on realm {}", realm))?
+                .auctions
+            {
+                if discover.matches(&auction_house) && !result.contains(&(realm, auction_house.id))
+                {
+                    result.push((realm, auction_house.id));
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Turns a configured realm into a connected realm id, remembering slugs already looked up.
+async fn resolve_realm(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: &RealmRef,
+    slugs: &mut HashMap<String, i64>,
+) -> Result<i64> {
+    match realm {
+        RealmRef::Id(id) => Ok(*id),
+        RealmRef::Slug(slug) => {
+            if let Some(id) = slugs.get(slug) {
+                return Ok(*id);
+            }
+            let id = get_realm(settings, access_token, slug)
+                .await
+                .with_context(|| format!("Couldn't look up realm {:?}", slug))?
+                .connected_realm
+                .id()
+                .ok_or_else(|| anyhow!("Realm {:?} has no connected realm id", slug))?;
+            slugs.insert(slug.clone(), id);
+            Ok(id)
+        }
+    }
+}
+
+async fn list_tracked_auction_houses(settings: &Settings, access_token: HeaderValue) -> Result<()> {
+    // Each connected realm is only looked up once, however many of its auction houses are tracked.
+    let mut realms: HashMap<i64, Result<(String, Vec<AuctionHouse>), String>> = HashMap::new();
+    for (realm, ah) in &tracked_auction_houses(settings, access_token.clone()).await? {
+        if !realms.contains_key(realm) {
+            let link = connected_realm_link(settings, *realm);
+            let described = match get_connected_realm(settings, access_token.clone(), link).await {
+                Ok(connected_realm) => {
+                    let auction_houses = get_auction_houses(settings, access_token.clone(), *realm)
+                        .await?
+                        .auctions;
+                    Ok((
+                        RealmName::Joined.pick(&connected_realm.realms),
+                        auction_houses,
+                    ))
+                }
+                Err(e) => Err(format!("{:#}", e)),
+            };
+            realms.insert(*realm, described);
+        }
+
+        match &realms[realm] {
+            Ok((realm_names, auction_houses)) => match auction_houses
+                .iter()
+                .find(|auction_house| auction_house.id == *ah)
+            {
+                Some(auction_house) => println!(
+                    "{} / {} - {} - {}",
+                    realm, ah, realm_names, auction_house.name
+                ),
+                None => println!(
+                    "{} / {} - {} - unknown auction house",
+                    realm, ah, realm_names
+                ),
+            },
+            Err(e) => println!("{} / {} - unknown realm ({})", realm, ah, e),
+        }
+    }
+    Ok(())
+}
+
+async fn inspect_item(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+    ah: i64,
+    item: i64,
+) -> Result<()> {
+    let auctions = get_auctions(settings, access_token, realm, ah, None)
+        .await
+        .context("Couldn't fetch list of auctions from battle.net")?
+        .ok_or_else(|| anyhow!("Battle.net didn't send any auctions"))?
+        .auctions
+        .into_iter()
+        .filter(|auction| auction.item.id == item)
+        .collect::<Vec<_>>();
+
+    println!("{} auctions for item {}", auctions.len(), item);
+    for auction in &auctions {
+        let per_unit = if auction.buyout > 0 && auction.quantity > 0 {
+            format!("{:.2}", auction.buyout as f64 / auction.quantity as f64)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "#{} bid {} buyout {} quantity {} (buyout {} each) {}",
+            auction.id, auction.bid, auction.buyout, auction.quantity, per_unit, auction.time_left
+        );
+    }
+
+    if let Some(data) = aggregate(settings, &auctions).get(&item) {
+        println!(
+            "count {} total_items {} min_buyout {} max_buyout {}",
+            data.auctions,
+            data.total_items,
+            data.min_buyout.map_or(0.0, |price| price.per_unit()),
+            data.max_buyout.map_or(0.0, |price| price.per_unit())
+        );
+    }
+    Ok(())
+}
+
+/// How one item's market changed between two snapshots.
+struct ItemMove {
+    id: i64,
+    count_before: i64,
+    count_now: i64,
+    price_before: Option<f64>,
+    price_now: Option<f64>,
+}
+
+impl ItemMove {
+    fn new(
+        settings: &Settings,
+        id: i64,
+        before: Option<&ItemData>,
+        now: Option<&ItemData>,
+    ) -> Self {
+        let price = |data: Option<&ItemData>| {
+            data.and_then(|data| data.floor_buyout(settings.min_trim_percent))
+                .map(|price| price.per_unit())
+        };
+        ItemMove {
+            id,
+            count_before: before.map_or(0, |data| data.auctions),
+            count_now: now.map_or(0, |data| data.auctions),
+            price_before: price(before),
+            price_now: price(now),
+        }
+    }
+
+    /// How far `min_buyout` moved, as a percentage of what it was. `None` unless the item had a
+    /// buyout in both snapshots.
+    fn price_change_percent(&self) -> Option<f64> {
+        match (self.price_before, self.price_now) {
+            (Some(before), Some(now)) if before > 0.0 => Some((now - before) / before * 100.0),
+            _ => None,
+        }
+    }
+}
+
+async fn print_diff(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+    ah: i64,
+    snapshot: &Path,
+    top: usize,
+) -> Result<()> {
+    let file = File::open(snapshot).with_context(|| format!("Couldn't open {:?}", snapshot))?;
+    let before: AuctionList = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Couldn't parse {:?}", snapshot))?;
+    let now = get_auctions(settings, access_token, realm, ah, None)
+        .await
+        .context("Couldn't fetch list of auctions from battle.net")?
+        .ok_or_else(|| anyhow!("Battle.net didn't send any auctions"))?;
+
+    let before = aggregate(
+        settings,
+        &dedupe_auctions(settings, before.auctions, realm, ah)?,
+    );
+    let now = aggregate(
+        settings,
+        &dedupe_auctions(settings, now.auctions, realm, ah)?,
+    );
+    let names_by_id = read_names_by_id(&settings.name_columns)?;
+    let name = |id: i64| names_by_id.get(&id).map_or("", String::as_str);
+
+    let moves = before
+        .keys()
+        .chain(now.keys())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|id| ItemMove::new(settings, *id, before.get(id), now.get(id)))
+        .collect::<Vec<_>>();
+
+    let mut price_moves = moves
+        .iter()
+        .filter_map(|item| Some((item, item.price_change_percent()?)))
+        .collect::<Vec<_>>();
+    price_moves.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+    println!("Biggest min_buyout changes:");
+    for (item, percent) in price_moves.iter().take(top) {
+        println!(
+            "{:+.1}% {:.2} -> {:.2} item {} {}",
+            percent,
+            item.price_before.unwrap_or_default(),
+            item.price_now.unwrap_or_default(),
+            item.id,
+            name(item.id)
+        );
+    }
+
+    let mut count_moves = moves
+        .iter()
+        .filter(|item| item.count_now != item.count_before)
+        .collect::<Vec<_>>();
+    count_moves.sort_by_key(|item| -(item.count_now - item.count_before).abs());
+    println!("Biggest count changes:");
+    for item in count_moves.iter().take(top) {
+        println!(
+            "{:+} {} -> {} item {} {}",
+            item.count_now - item.count_before,
+            item.count_before,
+            item.count_now,
+            item.id,
+            name(item.id)
+        );
+    }
+    Ok(())
+}
+
+/// Fetches an auction house every `interval` and redraws a table of the given items, forever.
+/// Meant to be raced against Ctrl-C, so it never returns unless something fails outright.
+async fn watch_items(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+    ah: i64,
+    items: &[i64],
+    interval: Duration,
+) -> Result<()> {
+    let names_by_id = read_names_by_id(&settings.name_columns)?;
+    let mut etag: Option<String> = None;
+    let mut last: HashMap<i64, (Option<f64>, i64)> = HashMap::new();
+    let mut table = String::new();
+
+    loop {
+        let status = match get_auctions(settings, access_token.clone(), realm, ah, etag.as_deref())
+            .await
+        {
+            Ok(Some(list)) => {
+                etag = list.etag.clone();
+                let auctions = dedupe_auctions(settings, list.auctions, realm, ah)?;
+                let by_items = aggregate(settings, &auctions);
+                table = format!(
+                    "{:<10} {:<32} {:>14} {:>10} {:>8} {:>7}\n",
+                    "item", "name", "min_buyout", "change", "count", "change"
+                );
+                for id in items {
+                    let data = by_items.get(id);
+                    let price = data.and_then(|data| data.min_buyout.map(|price| price.per_unit()));
+                    let count = data.map_or(0, |data| data.auctions);
+                    let (price_change, count_change) = match last.get(id) {
+                        Some((before, count_before)) => (
+                            match (price, before) {
+                                (Some(now), Some(before)) => {
+                                    format!("{:+.2}", (now - before) / COPPER_PER_GOLD)
+                                }
+                                _ => "-".to_string(),
+                            },
+                            format!("{:+}", count - count_before),
+                        ),
+                        None => (String::new(), String::new()),
+                    };
+                    table += &format!(
+                        "{:<10} {:<32} {:>14} {:>10} {:>8} {:>7}\n",
+                        id,
+                        names_by_id.get(id).map_or("", String::as_str),
+                        price.map_or("-".to_string(), |price| {
+                            format!("{:.2}g", price / COPPER_PER_GOLD)
+                        }),
+                        price_change,
+                        count,
+                        count_change
+                    );
+                    last.insert(*id, (price, count));
+                }
+                "Updated with a new snapshot".to_string()
+            }
+            Ok(None) => "No new snapshot since the last check".to_string(),
+            Err(e) => format!("Couldn't fetch auctions: {:#}", e),
+        };
+
+        // Clear the screen and move to the top left, so the table is redrawn in place.
+        print!("\x1b[2J\x1b[H");
+        println!("Realm {} AH {}. Ctrl-C to stop.\n", realm, ah);
+        print!("{}", table);
+        println!("\n{}. Checking again in {}s.", status, interval.as_secs());
+        io::stdout().flush().context("Couldn't write to stdout")?;
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn print_cardinality(
+    settings: &Settings,
+    access_token: HeaderValue,
+    top: usize,
+) -> Result<()> {
+    let names_by_id = read_names_by_id(&settings.name_columns)?;
+    let mut series = 0;
+    let mut variants: HashMap<i64, HashSet<(Option<i64>, Option<i64>)>> = HashMap::new();
+
+    for (realm, ah) in &tracked_auction_houses(settings, access_token.clone()).await? {
+        let auctions = get_auctions(settings, access_token.clone(), *realm, *ah, None)
+            .await
+            .with_context(|| format!("Couldn't fetch auctions for realm {} AH {}", realm, ah))?
+            .ok_or_else(|| anyhow!("Battle.net didn't send any auctions"))?
+            .auctions;
+        let auctions = dedupe_auctions(settings, auctions, *realm, *ah)?;
+        let by_items = aggregate(settings, &auctions);
+        let ah_series = by_items
+            .values()
+            .map(|data| data.variants.len().max(1))
+            .sum::<usize>();
+        println!(
+            "{} / {} - {} items, {} series",
+            realm,
+            ah,
+            by_items.len(),
+            ah_series
+        );
+        series += ah_series;
+
+        for auction in &auctions {
+            variants
+                .entry(auction.item.id)
+                .or_default()
+                .insert((auction.item.rand, auction.item.seed));
+        }
+    }
+
+    println!("Series per update: {}", series);
+    let mut variants = variants
+        .into_iter()
+        .map(|(id, variants)| (variants.len(), id))
+        .filter(|(count, _)| *count > 1)
+        .collect::<Vec<_>>();
+    println!(
+        "Items with random suffix variants: {}, which would add {} series if split by them",
+        variants.len(),
+        variants.iter().map(|(count, _)| count - 1).sum::<usize>()
+    );
+    variants.sort_unstable_by(|a, b| b.cmp(a));
+    for (count, id) in variants.iter().take(top) {
+        println!(
+            "{} variants - item {} {}",
+            count,
+            id,
+            names_by_id.get(id).map_or("", String::as_str)
+        );
+    }
+    Ok(())
+}
+
+/// Summarises how much the config tracks and how many requests an update makes, from the
+/// config alone. Auction houses found by `discover` can't be counted without asking
+/// battle.net, so only the requests to find them are.
+fn print_stats(settings: &Settings) {
+    // Slugs aren't resolved, so a slug and an id of the same connected realm count as two.
+    let mut realms = HashSet::new();
+    let mut slugs = HashSet::new();
+    let mut auction_houses = HashSet::new();
+    for entry in &settings.auction_houses {
+        let realm = match entry.realm() {
+            RealmRef::Id(id) => id.to_string(),
+            RealmRef::Slug(slug) => {
+                slugs.insert(slug.clone());
+                slug.clone()
+            }
+        };
+        for (_, ah) in entry.pairs(0) {
+            auction_houses.insert((realm.clone(), ah));
+        }
+        realms.insert(realm);
+    }
+
+    // The access token, then one request per auction house, assuming each is a single page.
+    let mut requests = 1 + auction_houses.len();
+    // Each of these asks about every tracked connected realm once.
+    for per_realm in [
+        settings.skip_offline_realms,
+        settings.realm_name.is_some(),
+        settings.realm_info,
+    ] {
+        if per_realm {
+            requests += realms.len();
+        }
+    }
+    if let Some(discover) = &settings.discover {
+        for realm in &discover.realms {
+            if let RealmRef::Slug(slug) = realm {
+                slugs.insert(slug.clone());
+            }
+        }
+        // Each listed realm's auction house list.
+        requests += discover.realms.len();
+    }
+    requests += slugs.len();
+
+    println!("Connected realms: {}", realms.len());
+    println!("Auction houses: {}", auction_houses.len());
+    match &settings.discover {
+        Some(discover) if discover.realms.is_empty() => println!(
+            "Plus whatever discover finds on every connected realm, each another request per \
+             update. Finding them takes a request for the realm index and 2 per connected \
+             realm, which can't be counted without asking battle.net"
+        ),
+        Some(discover) => println!(
+            "Plus whatever discover finds on {} connected realms, each another request per update",
+            discover.realms.len()
+        ),
+        None => {}
+    }
+    println!("Requests per update: at least {}", requests);
+    if let Some(cache) = &settings.item_cache {
+        println!(
+            "Plus up to {} item name lookups for itemcache",
+            cache.max_lookups
+        );
+    }
+    match settings.battle_net.rate_limit {
+        Some(rate) => println!(
+            "Shortest update at {} requests/second: {:.1}s",
+            rate,
+            requests as f64 / rate
+        ),
+        None => println!("No rate limit configured"),
+    }
+    let hourly = settings.battle_net.rate_limit_hourly.unwrap_or(36_000);
+    println!(
+        "Updates per hour within {} requests/hour: at most {}",
+        hourly,
+        hourly / requests
+    );
+}
+
+/// Whether a connected realm is up. If we can't tell, assume it is and let the
+/// auction request itself succeed or fail.
+async fn is_realm_online(settings: &Settings, access_token: HeaderValue, realm: i64) -> bool {
+    let link = connected_realm_link(settings, realm);
+    match get_connected_realm(settings, access_token, link).await {
+        Ok(connected_realm) => connected_realm
+            .status
+            .is_none_or(|status| status.kind != "DOWN"),
+        Err(e) => {
+            eprintln!("Couldn't check status of realm {}: {:#}", realm, e);
+            true
+        }
+    }
+}
+
+fn get_settings(
+    config: Option<&Path>,
+    format: Option<ConfigFormat>,
+    items_file: Option<&Path>,
+) -> Result<Settings> {
+    let mut settings = Figment::new();
+    if let Some(path) = config {
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+        settings = match format {
+            ConfigFormat::Toml => settings.merge(Toml::file(path)),
+            ConfigFormat::Json => settings.merge(Json::file(path)),
+            ConfigFormat::Yaml => settings.merge(Yaml::file(path)),
+        };
+    }
+    settings = settings.merge(Env::prefixed("AH_").split("_"));
+    if let Some(path) = items_file {
+        settings = settings.merge(Serialized::default("itemsfile", path));
+    }
+    let mut settings: Settings = settings.extract()?;
+    if let Some(path) = &settings.items_file {
+        let items = read_items_file(path)
+            .with_context(|| format!("Couldn't read items file {:?}", path))?;
+        settings.include_items.extend(items);
+    }
+
+    validate_extra_tags(&settings.influxdb.extra_tags)?;
+    if matches!(settings.battle_net.rate_limit, Some(rate) if rate <= 0.0) {
+        return Err(anyhow!("ratelimit must be above 0"));
+    }
+    if settings.battle_net.rate_limit_hourly == Some(0) {
+        return Err(anyhow!("ratelimithourly must be above 0"));
+    }
+    if matches!(settings.min_price_copper, Some(min) if min < 0) {
+        return Err(anyhow!("minpricecopper can't be negative"));
+    }
+    if matches!(settings.min_trim_percent, Some(percent) if !(0.0..100.0).contains(&percent)) {
+        return Err(anyhow!("mintrimpercent must be at least 0 and below 100"));
+    }
+    if settings.influxdb.price_in_gold && settings.influxdb.price_denominations {
+        return Err(anyhow!(
+            "priceingold and pricedenominations can't both be set, pick one"
+        ));
+    }
+    if settings.influxdb.client_cert.is_some() != settings.influxdb.client_key.is_some() {
+        return Err(anyhow!(
+            "influxdb clientcert and clientkey must be set together"
+        ));
+    }
+    // Read now, so a bad certificate fails straight away rather than at the first write.
+    output::influxdb_client_builder(&settings.influxdb)?;
+    if settings.influxdb.gold_precision.is_some() && !settings.influxdb.price_in_gold {
+        return Err(anyhow!("goldprecision only applies with priceingold set"));
+    }
+    if settings
+        .price_buckets
+        .first()
+        .is_some_and(|first| *first <= 0)
+        || settings
+            .price_buckets
+            .windows(2)
+            .any(|pair| pair[0] >= pair[1])
+    {
+        return Err(anyhow!(
+            "pricebuckets must be above 0 and in increasing order"
+        ));
+    }
+    if settings.name_columns.is_empty() {
+        return Err(anyhow!("namecolumns needs at least one column"));
+    }
+    if matches!(&settings.influxdb.fields, Some(fields) if fields.is_empty()) {
+        return Err(anyhow!("At least one field must be written to InfluxDB"));
+    }
+    if let Some((field, _)) = settings
+        .influxdb
+        .measurements
+        .iter()
+        .find(|(_, measurement)| measurement.is_empty())
+    {
+        return Err(anyhow!("Measurement for {:?} can't be empty", field));
+    }
+    if let Some(quarantine) = &settings.quarantine {
+        if settings.state_file.is_none() {
+            return Err(anyhow!(
+                "quarantine needs statefile to be set, to count failures between runs"
+            ));
+        }
+        if quarantine.failures == 0 || quarantine.max_runs == 0 {
+            return Err(anyhow!(
+                "quarantine failures and maxruns must be at least 1"
+            ));
+        }
+    }
+    if let Some(weights) = &settings.competitiveness {
+        let all = [weights.listings, weights.undercut_gap, weights.spread];
+        if all.iter().any(|weight| *weight < 0.0) || all.iter().all(|weight| *weight == 0.0) {
+            return Err(anyhow!(
+                "competitiveness weights can't be negative, and at least one must be above 0"
+            ));
+        }
+        if weights.listings_scale <= 0.0 {
+            return Err(anyhow!("competitiveness listingsscale must be above 0"));
+        }
+    }
+    if !settings.alerts.is_empty() {
+        if settings.alert_webhook.is_none() {
+            return Err(anyhow!("alerts need alertwebhook to be set"));
+        }
+        if settings.state_file.is_none() {
+            return Err(anyhow!(
+                "alerts need statefile to be set, to remember prices between runs"
+            ));
+        }
+    }
+    for (realm, datacenter) in &settings.datacenters {
+        if i64::from_str(realm).is_err() {
+            return Err(anyhow!(
+                "datacenters are keyed by connected realm id, {:?} isn't one",
+                realm
+            ));
+        }
+        if datacenter.is_empty() {
+            return Err(anyhow!("Data center for realm {} can't be empty", realm));
+        }
+    }
+    for (item, tags) in &settings.item_tags {
+        if i64::from_str(item).is_err() {
+            return Err(anyhow!(
+                "itemtags are keyed by item id, {:?} isn't one",
+                item
+            ));
+        }
+        validate_extra_tags(tags).with_context(|| format!("Invalid itemtags for item {}", item))?;
+        if let Some(key) = tags
+            .keys()
+            .find(|key| settings.influxdb.extra_tags.contains_key(*key))
+        {
+            return Err(anyhow!(
+                "Tag {:?} of item {} is also in extratags, set it in one place",
+                key,
+                item
+            ));
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Writes a config with just the settings every run needs, then reads it back and
+/// authenticates with it to check that it works.
+async fn init_config(args: &InitArgs, path: &Path) -> Result<()> {
+    if path.exists() && !args.force {
+        return Err(anyhow!(
+            "{:?} already exists, pass --force to overwrite it",
+            path
+        ));
+    }
+
+    let region = init_value(
+        &args.region,
+        "region",
+        "Battle.net region (us, eu, kr, tw or cn)",
+        None,
+        false,
+    )?;
+    if !["us", "eu", "kr", "tw", "cn"].contains(&region.as_str()) {
+        return Err(anyhow!(
+            "Region must be us, eu, kr, tw or cn, not {:?}",
+            region
+        ));
+    }
+    let client_id = init_value(
+        &args.client_id,
+        "client-id",
+        "Battle.net client id",
+        None,
+        false,
+    )?;
+    let client_secret = init_value(
+        &args.client_secret,
+        "client-secret",
+        "Battle.net client secret",
+        None,
+        true,
+    )?;
+    let host = init_value(
+        &args.influxdb_host,
+        "influxdb-host",
+        "InfluxDB URL",
+        Some("http://localhost:8086"),
+        false,
+    )?;
+    let org = init_value(
+        &args.influxdb_org,
+        "influxdb-org",
+        "InfluxDB organization",
+        None,
+        false,
+    )?;
+    let token = init_value(
+        &args.influxdb_token,
+        "influxdb-token",
+        "InfluxDB token",
+        None,
+        true,
+    )?;
+    let bucket = init_value(
+        &args.influxdb_bucket,
+        "influxdb-bucket",
+        "InfluxDB bucket",
+        Some("auctions"),
+        false,
+    )?;
+
+    // JSON strings are valid TOML basic strings, escapes and all.
+    let quote = |value: &str| serde_json::to_string(value).expect("Strings always serialize");
+    let config = format!(
+        "# Written by `init`. Run `dump-schema` to see every other setting.\n\
+         \n\
+         # Auction houses to track, as [connected realm id, auction house id] pairs.\n\
+         # Run `list-auction-houses` to see which are available.\n\
+         auctionhouses = []\n\
+         \n\
+         [influxdb]\n\
+         host = {}\n\
+         org = {}\n\
+         token = {}\n\
+         bucket = {}\n\
+         \n\
+         [battlenet]\n\
+         region = {}\n\
+         clientid = {}\n\
+         clientsecret = {}\n",
+        quote(&host),
+        quote(&org),
+        quote(&token),
+        quote(&bucket),
+        quote(&region),
+        quote(&client_id),
+        quote(&client_secret),
+    );
+    fs::write(path, config).with_context(|| format!("Couldn't write config to {:?}", path))?;
+    eprintln!("Wrote {:?}", path);
+
+    let settings = get_settings(Some(path), Some(ConfigFormat::Toml), None)
+        .with_context(|| format!("Couldn't parse the config written to {:?}", path))?;
+    get_access_token(&settings.battle_net)
+        .await
+        .context("Couldn't authenticate with battle.net using the new config")?;
+    eprintln!(
+        "Authenticated with battle.net. Run `list-auction-houses` and add some to \
+         auctionhouses in {:?} to start tracking them.",
+        path
+    );
+    Ok(())
+}
+
+/// Takes an `init` setting from its flag, or asks for it when built with the `init` feature.
+/// Without the feature, a setting with no flag or default is an error.
+fn init_value(
+    flag: &Option<String>,
+    name: &str,
+    prompt: &str,
+    default: Option<&str>,
+    secret: bool,
+) -> Result<String> {
+    if let Some(value) = flag {
+        return Ok(value.clone());
+    }
+
+    #[cfg(feature = "init")]
+    {
+        let theme = dialoguer::theme::ColorfulTheme::default();
+        let value = if secret {
+            dialoguer::Password::with_theme(&theme)
+                .with_prompt(prompt)
+                .interact()
+        } else {
+            let mut input = dialoguer::Input::<String>::with_theme(&theme).with_prompt(prompt);
+            if let Some(default) = default {
+                input = input.default(default.to_string());
+            }
+            input.interact_text()
+        };
+        value.with_context(|| format!("Couldn't ask for --{}", name))
+    }
+
+    #[cfg(not(feature = "init"))]
+    {
+        let _ = (prompt, secret);
+        default.map(str::to_string).ok_or_else(|| {
+            anyhow!(
+                "--{} is needed, or build with `--features init` to be asked for it",
+                name
+            )
+        })
+    }
+}
+
+/// Reads item ids, one per line. Blank lines and lines starting with `#` are skipped, as is
+/// anything after a comma, such as a name. Every malformed line is reported at once.
+fn read_items_file(path: &Path) -> Result<Vec<i64>> {
+    let contents = fs::read_to_string(path)?;
+    let mut items = vec![];
+    let mut malformed = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let id = line.split(',').next().unwrap_or_default().trim();
+        match i64::from_str(id) {
+            Ok(id) if id > 0 => items.push(id),
+            _ => malformed.push(format!("line {} ({:?})", number + 1, line)),
+        }
+    }
+    if !malformed.is_empty() {
+        return Err(anyhow!("Malformed item ids on {}", malformed.join(", ")));
+    }
+    Ok(items)
+}
+
+fn validate_extra_tags(tags: &BTreeMap<String, String>) -> Result<()> {
+    for (key, value) in tags {
+        if key.is_empty() || value.is_empty() {
+            return Err(anyhow!(
+                "Extra tag {:?} = {:?} must have a non-empty key and value",
+                key,
+                value
+            ));
+        }
+        if key.starts_with('_') {
+            return Err(anyhow!(
+                "Extra tag {:?} can't start with an underscore, InfluxDB reserves those",
+                key
+            ));
+        }
+        if BUILTIN_TAGS.contains(&key.as_str()) {
+            return Err(anyhow!(
+                "Extra tag {:?} would overwrite a tag this tool already sets",
+                key
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn update_prices(
+    settings: &Settings,
+    args: &UpdateArgs,
+    output: &mut Output,
+    names_by_id: &mut HashMap<i64, String>,
+    item_cache: Option<&mut ItemNameCache>,
+    mut state: Option<&mut StateFile>,
+    access_token: HeaderValue,
+    realm: i64,
+    realm_name: Option<&str>,
+    ah: i64,
+    check_schema: bool,
+    series_written: Option<&mut HashSet<String>>,
+) -> Result<AuctionHouseUpdate> {
+    let etag = state.as_deref().and_then(|state| state.etag(realm, ah));
+    let auction_list = match get_auctions(&settings, access_token.clone(), realm, ah, etag).await {
+        Ok(Some(auction_list)) => auction_list,
+        Ok(None) => {
+            eprintln!(
+                "Auctions for realm {} AH {} haven't changed, skipping",
+                realm, ah
+            );
+            return Ok(AuctionHouseUpdate::Unchanged);
+        }
+        Err(e) if e.is::<MaintenanceSnapshot>() && !args.fail_fast => {
+            eprintln!(
+                "Realm {} AH {} is likely in maintenance ({}), skipping",
+                realm, ah, e
+            );
+            return Ok(AuctionHouseUpdate::Skipped);
+        }
+        Err(e) => return Err(e.context("Couldn't fetch list of auctions from battle.net")),
+    };
+
+    // Stamped here rather than by InfluxDB, so points written later (such as from a dead
+    // letter) still land at the time they were fetched.
+    let mut stamps = Timestamps::now()?;
+    if let Some(modified) = auction_list.modified {
+        stamps.snapshot = modified;
+    }
+    if let Some(dir) = &args.save_raw {
+        save_raw_snapshot(dir, &auction_list, realm, ah, stamps.snapshot)?;
+    }
+
+    let new_etag = auction_list.etag;
+    let auctions = dedupe_auctions(settings, auction_list.auctions, realm, ah)?;
+    if args.fail_fast {
+        for auction in &auctions {
+            if let Some(problem) = auction_problem(auction) {
+                return Err(anyhow!("Auction has {}: {:#?}", problem, auction));
+            }
+        }
+        let problems = schema_problems(&auctions);
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "Snapshot doesn't look as expected: {}",
+                problems.join(", ")
+            ));
+        }
+    }
+    if check_schema {
+        let problems = schema_problems(&auctions);
+        if !problems.is_empty() {
+            eprintln!("**********************************************************************");
+            eprintln!(
+                "WARNING: auctions for realm {} AH {} don't look as expected.",
+                realm, ah
+            );
+            eprintln!("The Blizzard API may have changed, so the data written may be wrong:");
+            for problem in &problems {
+                eprintln!("  - {}", problem);
+            }
+            eprintln!("**********************************************************************");
+        }
+    }
+    let mut by_items = aggregate(settings, &auctions);
+    if let Some(max) = args.max_items {
+        let dropped = cap_items(&mut by_items, max);
+        if dropped > 0 {
+            eprintln!(
+                "Realm {} AH {} has {} items, only writing the {} with the most auctions",
+                realm,
+                ah,
+                max + dropped,
+                max
+            );
+        }
+    }
+
+    if let Some(cache) = item_cache {
+        cache
+            .name_unknown_items(settings, access_token, names_by_id, &by_items)
+            .await;
+    }
+
+    let timestamp = settings.influxdb.timestamp("auctions", stamps);
+
+    match output {
+        Output::Points(writer) => {
+            let points = if args.raw_points {
+                raw_auction_points(settings, &auctions, realm, ah, stamps)?
+            } else if args.summary_only {
+                build_summary_point(
+                    settings,
+                    &auctions,
+                    realm,
+                    ah,
+                    stamps,
+                    Some(auction_list.parse_time),
+                    Some(&by_items),
+                )?
+                .into_iter()
+                .collect()
+            } else {
+                snapshot_points(
+                    settings,
+                    &auctions,
+                    &by_items,
+                    names_by_id,
+                    realm,
+                    realm_name,
+                    ah,
+                    stamps,
+                    Some(auction_list.parse_time),
+                )?
+            };
+            if let Some(seen) = series_written {
+                warn_duplicate_series(seen, &points)?;
+            }
+            writer.write(points).await?;
+        }
+        Output::Ndjson => {
+            output::write_ndjson(&item_records(
+                settings,
+                &by_items,
+                names_by_id,
+                realm,
+                ah,
+                timestamp,
+            ))?;
+        }
+        Output::OpenMetrics(file) => {
+            for record in item_records(settings, &by_items, names_by_id, realm, ah, timestamp) {
+                add_item_metrics(file, &record);
+            }
+        }
+        #[cfg(feature = "kafka")]
+        Output::Kafka(sink) => {
+            let records = item_records(settings, &by_items, names_by_id, realm, ah, timestamp)
+                .into_iter()
+                .map(|record| (record.item_id.to_string(), record))
+                .collect::<Vec<_>>();
+            sink.publish(&records)
+                .await
+                .context("Couldn't publish items to Kafka")?;
+        }
+    }
+
+    if let (Some(state), Some(url)) = (state.as_deref_mut(), &settings.alert_webhook) {
+        check_alerts(settings, url, state, &by_items, names_by_id, realm, ah).await;
+    }
+
+    // Only remembered once the data's safely away, so a failed write gets fetched again.
+    if let (Some(state), Some(etag)) = (state, new_etag) {
+        state.set_etag(realm, ah, etag);
+    }
+
+    Ok(AuctionHouseUpdate::Updated(UpdatedAuctionHouse {
+        auctions: auctions.len(),
+        by_items,
+        snapshot: stamps.snapshot,
+    }))
+}
+
+/// What `update_prices` did with an auction house.
+enum AuctionHouseUpdate {
+    Updated(UpdatedAuctionHouse),
+    /// Battle.net still has the snapshot we last wrote.
+    Unchanged,
+    /// Battle.net looked to be in maintenance, so there was nothing to write.
+    Skipped,
+}
+
+/// What was written for an auction house that had a new snapshot.
+struct UpdatedAuctionHouse {
+    auctions: usize,
+    by_items: HashMap<i64, ItemData>,
+    /// When battle.net took the snapshot, or else when it was fetched, in nanoseconds since
+    /// the unix epoch.
+    snapshot: i64,
+}
+
+/// Posts to the webhook for every alert whose item's `min_buyout` crossed its price since the
+/// last snapshot of this auction house, then remembers the new prices for next time. Failing
+/// to post is only logged, as it's no reason to lose the prices.
+async fn check_alerts(
+    settings: &Settings,
+    url: &str,
+    state: &mut StateFile,
+    by_items: &HashMap<i64, ItemData>,
+    names_by_id: &HashMap<i64, String>,
+    realm: i64,
+    ah: i64,
+) {
+    let mut prices = vec![];
+    for alert in &settings.alerts {
+        let price = match by_items
+            .get(&alert.item)
+            .and_then(|data| data.floor_buyout(settings.min_trim_percent))
+        {
+            Some(price) => price.per_unit(),
+            None => continue,
+        };
+        prices.push((alert.item, price));
+
+        let before = match state.alert_price(realm, ah, alert.item) {
+            Some(before) if alert.crossed(before, price) => before,
+            _ => continue,
+        };
+        let message = format!(
+            "{} ({}) on realm {} AH {} went {} {:.2}g: now {:.2}g, was {:.2}g",
+            names_by_id.get(&alert.item).map_or("Item", String::as_str),
+            alert.item,
+            realm,
+            ah,
+            match alert.direction {
+                AlertDirection::Below => "below",
+                AlertDirection::Above => "above",
+            },
+            alert.price / COPPER_PER_GOLD,
+            price / COPPER_PER_GOLD,
+            before / COPPER_PER_GOLD
+        );
+        if let Err(e) = send_alert(url, &message).await {
+            eprintln!("Couldn't send price alert ({}): {:#}", message, e);
+        }
+    }
+
+    for (item, price) in prices {
+        state.set_alert_price(realm, ah, item, price);
+    }
+}
+
+async fn send_alert(url: &str, message: &str) -> Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .json(&serde_json::json!({ "content": message }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Heuristics for spotting a snapshot whose shape has changed underneath us. These are only
+/// hints; anything returned here is worth a warning, not a failure.
+fn schema_problems(auctions: &[Auction]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if auctions.is_empty() {
+        problems.push("the snapshot has no auctions at all".to_string());
+        return problems;
+    }
+
+    let bad_ids = auctions
+        .iter()
+        .filter(|auction| !has_item_id(auction))
+        .count();
+    if bad_ids > 0 {
+        problems.push(format!("{} auctions have no usable item id", bad_ids));
+    }
+    let bad_quantities = auctions
+        .iter()
+        .filter(|auction| !has_quantity(auction))
+        .count();
+    if bad_quantities > 0 {
+        problems.push(format!("{} auctions have no quantity", bad_quantities));
+    }
+    let priced: Vec<i64> = auctions
+        .iter()
+        .map(|auction| auction.buyout.max(auction.bid))
+        .filter(|price| *price > 0)
+        .collect();
+    if priced.is_empty() {
+        problems.push("no auction has a bid or buyout".to_string());
+    } else if priced.len() >= MIN_PRICES_FOR_UNIT_CHECK {
+        // Players can set prices to the copper, but most round to at least the silver, so in
+        // a real snapshot plenty end in 00. By chance alone only 1 in 100 would, so hardly any
+        // doing so suggests the prices are in some other unit. Only a hint, so only a warning.
+        let whole_silver = priced.iter().filter(|price| *price % 100 == 0).count();
+        if whole_silver * 10 < priced.len() {
+            problems.push(format!(
+                "only {} of {} prices are a whole number of silver, they may not be in copper",
+                whole_silver,
+                priced.len()
+            ));
+        }
+    }
+    let unknown_durations = auctions
+        .iter()
+        .filter(|auction| !has_known_time_left(auction))
+        .count();
+    if unknown_durations > 0 {
+        problems.push(format!(
+            "{} auctions have an unrecognised time_left",
+            unknown_durations
+        ));
+    }
+    problems
+}
+
+/// The first thing that's wrong with a single auction, for `--fail-fast`.
+fn auction_problem(auction: &Auction) -> Option<&'static str> {
+    if !has_item_id(auction) {
+        Some("no usable item id")
+    } else if !has_quantity(auction) {
+        Some("no quantity")
+    } else if !has_known_time_left(auction) {
+        Some("an unrecognised time_left")
+    } else {
+        None
+    }
+}
+
+fn has_item_id(auction: &Auction) -> bool {
+    auction.item.id > 0
+}
+
+fn has_quantity(auction: &Auction) -> bool {
+    auction.quantity > 0
+}
+
+fn has_known_time_left(auction: &Auction) -> bool {
+    matches!(
+        auction.time_left.as_str(),
+        "SHORT" | "MEDIUM" | "LONG" | "VERY_LONG"
+    )
+}
+
+/// One item's statistics on one auction house, for outputs other than InfluxDB.
+#[derive(Serialize, Debug)]
+struct ItemRecord<'a> {
+    item_id: i64,
+    item_name: Option<&'a str>,
+    realm_id: i64,
+    ah_id: i64,
+    /// Nanoseconds since the unix epoch.
+    timestamp: i64,
+    count: i64,
+    total_items: i64,
+    min_buyout: Option<f64>,
+    max_buyout: Option<f64>,
+    avg_bid_to_buyout_ratio: Option<f64>,
+}
+
+fn item_records<'a>(
+    settings: &Settings,
+    by_items: &HashMap<i64, ItemData>,
+    names_by_id: &'a HashMap<i64, String>,
+    realm: i64,
+    ah: i64,
+    timestamp: i64,
+) -> Vec<ItemRecord<'a>> {
+    by_items
+        .iter()
+        .map(|(id, data)| ItemRecord {
+            item_id: *id,
+            item_name: names_by_id.get(id).map(String::as_str),
+            realm_id: realm,
+            ah_id: ah,
+            timestamp,
+            count: data.auctions,
+            total_items: data.total_items,
+            min_buyout: data
+                .floor_buyout(settings.min_trim_percent)
+                .map(|price| price.per_unit()),
+            max_buyout: data.max_buyout.map(|price| price.per_unit()),
+            avg_bid_to_buyout_ratio: data.avg_bid_to_buyout_ratio(),
+        })
+        .collect()
+}
+
+/// Adds one item's statistics as gauges, labelled the same way as InfluxDB points are tagged.
+fn add_item_metrics(file: &mut OpenMetricsFile, record: &ItemRecord) {
+    let mut labels = vec![
+        ("item_id", record.item_id.to_string()),
+        ("realm_id", record.realm_id.to_string()),
+        ("ah_id", record.ah_id.to_string()),
+    ];
+    if let Some(name) = record.item_name {
+        labels.push(("item_name", name.to_string()));
+    }
+
+    file.gauge(
+        "wow_auction_count",
+        "Number of auctions listing the item",
+        &labels,
+        record.count as f64,
+    );
+    file.gauge(
+        "wow_auction_total_items",
+        "Number of the item listed across all auctions",
+        &labels,
+        record.total_items as f64,
+    );
+    if let Some(price) = record.min_buyout {
+        file.gauge(
+            "wow_auction_min_buyout_copper",
+            "Lowest buyout price per unit, in copper",
+            &labels,
+            price,
+        );
+    }
+    if let Some(price) = record.max_buyout {
+        file.gauge(
+            "wow_auction_max_buyout_copper",
+            "Highest buyout price per unit, in copper",
+            &labels,
+            price,
+        );
+    }
+    if let Some(ratio) = record.avg_bid_to_buyout_ratio {
+        file.gauge(
+            "wow_auction_avg_bid_to_buyout_ratio",
+            "Average ratio of bid to buyout across auctions with both",
+            &labels,
+            ratio,
+        );
+    }
+}
+
+/// Keeps only the first of any auctions sharing an id, such as from pages overlapping, and
+/// reports any that were dropped as `duplicateauctions` says to.
+fn dedupe_auctions(
+    settings: &Settings,
+    mut auctions: Vec<Auction>,
+    realm: i64,
+    ah: i64,
+) -> Result<Vec<Auction>> {
+    let before = auctions.len();
+    let mut seen = HashSet::with_capacity(before);
+    auctions.retain(|auction| seen.insert(auction.id));
+    let duplicates = before - auctions.len();
+    if duplicates > 0 {
+        match settings.duplicate_auctions {
+            DuplicateAuctions::Ignore => {}
+            DuplicateAuctions::Warn => eprintln!(
+                "Dropped {} duplicate auctions for realm {} AH {}",
+                duplicates, realm, ah
+            ),
+            DuplicateAuctions::Error => {
+                return Err(anyhow!(
+                    "Snapshot listed {} auctions more than once",
+                    duplicates
+                ))
+            }
+        }
+    }
+    Ok(auctions)
+}
+
+/// Whether an auction's buyout is under `minpricecopper` per unit. Auctions without a buyout
+/// never are, as they don't affect prices anyway.
+fn is_below_min_price(settings: &Settings, auction: &Auction) -> bool {
+    match settings.min_price_copper {
+        Some(min) if auction.buyout > 0 && auction.quantity > 0 => {
+            (auction.buyout as i128) < min as i128 * auction.quantity as i128
+        }
+        _ => false,
+    }
+}
+
+/// Summarises a snapshot of auctions into per-item statistics, keyed by item id.
+fn aggregate(settings: &Settings, auctions: &[Auction]) -> HashMap<i64, ItemData> {
+    let mut by_items: HashMap<i64, ItemData> = HashMap::new();
+
+    for auction in auctions {
+        if !settings.include_items.is_empty() && !settings.include_items.contains(&auction.item.id)
+        {
+            continue;
+        }
+        if is_below_min_price(settings, auction) {
+            if settings.count_below_min_price {
+                by_items.entry(auction.item.id).or_default().count(auction);
+            }
+            continue;
+        }
+        let minutes = settings.time_left_minutes(&auction.time_left);
+        let data = by_items.entry(auction.item.id).or_default();
+        data.add(auction, minutes);
+        if settings.influxdb.random_suffix_tags {
+            data.variants
+                .entry((auction.item.rand, auction.item.seed))
+                .or_default()
+                .add(auction, minutes);
+        }
+    }
+    for data in by_items.values_mut() {
+        data.prices.sort();
+        for variant in data.variants.values_mut() {
+            variant.prices.sort();
+        }
+    }
+
+    by_items
+}
+
+/// Keeps only the `max` items with the most auctions, and says how many others were dropped.
+fn cap_items(by_items: &mut HashMap<i64, ItemData>, max: usize) -> usize {
+    if by_items.len() <= max {
+        return 0;
+    }
+    let mut ranked = by_items
+        .iter()
+        .map(|(id, data)| (data.auctions, *id))
+        .collect::<Vec<_>>();
+    ranked.sort_unstable_by(|a, b| b.cmp(a));
+    for (_, id) in &ranked[max..] {
+        by_items.remove(id);
+    }
+    ranked.len() - max
+}
+
+/// Turns aggregated item statistics for one auction house into points ready for InfluxDB.
+/// `realm` and `ah` are usually ids, but are `all` for the cross-realm rollup, and `ah` is
+/// `combined` for a realm's auction houses combined.
+/// `realm_name` is only given when `realmname` is set.
+fn build_points(
+    settings: &Settings,
+    by_items: &HashMap<i64, ItemData>,
+    names_by_id: &HashMap<i64, String>,
+    realm: impl Display,
+    realm_name: Option<&str>,
+    ah: impl Display,
+    stamps: Timestamps,
+) -> Result<Vec<DataPoint>> {
+    let mut points = vec![];
+    for (id, data) in by_items {
+        let mut tags = vec![
+            ("item_id", id.to_string()),
+            ("realm_id", realm.to_string()),
+            ("ah_id", ah.to_string()),
+        ];
+        if let Some(name) = names_by_id.get(id) {
+            tags.push(("item_name", name.clone()));
+        }
+        if let Some(realm_name) = realm_name {
+            tags.push(("realm_name", realm_name.to_string()));
+        }
+        if let Some(datacenter) = settings.datacenter(&realm) {
+            tags.push(("datacenter", datacenter.to_string()));
+        }
+        if settings.special_items.contains(id) {
+            tags.push(("special", "true".to_string()));
+        }
+        for (key, value) in settings.item_tags(*id).into_iter().flatten() {
+            tags.push((key.as_str(), value.clone()));
+        }
+        if data.variants.is_empty() {
+            points.extend(item_points(settings, tags, data, stamps)?);
+            continue;
+        }
+        for ((rand, seed), variant) in &data.variants {
+            let mut tags = tags.clone();
+            if let Some(rand) = rand {
+                tags.push(("rand", rand.to_string()));
+            }
+            if let Some(seed) = seed {
+                tags.push(("seed", seed.to_string()));
+            }
+            points.extend(item_points(settings, tags, variant, stamps)?);
+        }
+    }
+
+    Ok(points)
+}
+
+/// The points for one item, or one random suffix variant of it, with the given built-in tags.
+fn item_points<'a>(
+    settings: &'a Settings,
+    mut tags: Vec<(&'a str, String)>,
+    data: &ItemData,
+    stamps: Timestamps,
+) -> Result<Vec<DataPoint>> {
+    let influxdb = &settings.influxdb;
+    for (key, value) in &influxdb.extra_tags {
+        tags.push((key.as_str(), value.clone()));
+    }
+    let mut item = ItemPoints {
+        influxdb,
+        stamps,
+        tags,
+        points: BTreeMap::new(),
+    };
+
+    if influxdb.writes(Field::Count) {
+        item.add(Field::Count, |point| point.field("count", data.auctions));
+    }
+    if influxdb.writes(Field::TotalItems) {
+        item.add(Field::TotalItems, |point| {
+            point.field("total_items", data.total_items)
+        });
+    }
+    if influxdb.writes(Field::MinBuyout) {
+        let price = data
+            .floor_buyout(settings.min_trim_percent)
+            .map_or(0.0, |price| price.per_unit());
+        item.add(Field::MinBuyout, |point| {
+            price_field(influxdb, point, "min_buyout", price)
+        });
+    }
+    if influxdb.writes(Field::MaxBuyout) {
+        // Skipped entirely when every auction was bid-only, rather than writing a bogus 0.
+        if let Some(price) = data.max_buyout {
+            item.add(Field::MaxBuyout, |point| {
+                price_field(influxdb, point, "max_buyout", price.per_unit())
+            });
+        }
+    }
+    if influxdb.writes(Field::AvgBidToBuyoutRatio) {
+        if let Some(ratio) = data.avg_bid_to_buyout_ratio() {
+            item.add(Field::AvgBidToBuyoutRatio, |point| {
+                point.field("avg_bid_to_buyout_ratio", ratio)
+            });
+        }
+    }
+    if let (true, Some(wall_size)) = (influxdb.writes(Field::LikelyWallCount), settings.wall_size) {
+        item.add(Field::LikelyWallCount, |point| {
+            point.field("likely_wall_count", data.likely_walls(wall_size))
+        });
+    }
+    if influxdb.writes(Field::AvgTimeLeftMinutes) {
+        if let Some(minutes) = data.avg_time_left_minutes() {
+            item.add(Field::AvgTimeLeftMinutes, |point| {
+                point.field("avg_time_left_minutes", minutes)
+            });
+        }
+    }
+    if influxdb.writes(Field::PriceBuckets) && !settings.price_buckets.is_empty() {
+        let counts = data.price_buckets(&settings.price_buckets);
+        item.add(Field::PriceBuckets, |point| {
+            counts
+                .into_iter()
+                .enumerate()
+                .fold(point, |point, (bucket, count)| {
+                    point.field(price_bucket_name(&settings.price_buckets, bucket), count)
+                })
+        });
+    }
+    if influxdb.writes(Field::UndercutGap) {
+        // Skipped when there's only one price, as there's nothing to undercut.
+        if let Some(gap) = data.undercut_gap(settings.min_trim_percent) {
+            item.add(Field::UndercutGap, |point| {
+                price_field(influxdb, point, "undercut_gap", gap)
+            });
+        }
+    }
+    if let (true, Some(weights)) = (
+        influxdb.writes(Field::Competitiveness),
+        &settings.competitiveness,
+    ) {
+        if let Some(score) = data.competitiveness(weights, settings.min_trim_percent) {
+            item.add(Field::Competitiveness, |point| {
+                point.field("competitiveness", score)
+            });
+        }
+    }
+
+    item.build()
+}
+
+/// The field counting auctions in the `bucket`th price bucket, such as `price_bucket_0_10000`
+/// or, for the last, `price_bucket_50000_plus`.
+fn price_bucket_name(boundaries: &[i64], bucket: usize) -> String {
+    let from = match bucket {
+        0 => 0,
+        _ => boundaries[bucket - 1],
+    };
+    match boundaries.get(bucket) {
+        Some(to) => format!("price_bucket_{}_{}", from, to),
+        None => format!("price_bucket_{}_plus", from),
+    }
+}
+
+/// One item's points, one for each measurement its fields are routed to.
+struct ItemPoints<'a> {
+    influxdb: &'a InfluxdbSettings,
+    stamps: Timestamps,
+    /// Given to every point, in this order.
+    tags: Vec<(&'a str, String)>,
+    points: BTreeMap<&'a str, DataPointBuilder>,
+}
+
+impl<'a> ItemPoints<'a> {
+    /// Adds to the point for `field`'s measurement, starting it if this is its first field.
+    fn add(&mut self, field: Field, add: impl FnOnce(DataPointBuilder) -> DataPointBuilder) {
+        let measurement = self.influxdb.measurement(field);
+        let point = match self.points.remove(measurement) {
+            Some(point) => point,
+            None => self.tags.iter().fold(
+                DataPoint::builder(measurement)
+                    .timestamp(self.influxdb.timestamp(measurement, self.stamps)),
+                |point, (key, value)| point.tag(*key, value.as_str()),
+            ),
+        };
+        self.points.insert(measurement, add(point));
+    }
+
+    fn build(self) -> Result<Vec<DataPoint>> {
+        self.points
+            .into_values()
+            .map(|point| Ok(point.build()?))
+            .collect()
+    }
+}
+
+/// Warns about any points that InfluxDB would merge into one, with each other or with any
+/// point already in `seen`.
+fn warn_duplicate_series(seen: &mut HashSet<String>, points: &[DataPoint]) -> Result<()> {
+    for key in output::duplicate_series(seen, points)? {
+        eprintln!(
+            "WARNING: more than one point for {}, InfluxDB will only keep the last",
+            key
+        );
+    }
+    Ok(())
+}
+
+/// Every point written for one snapshot of an auction house: its items, groups and summary.
+#[allow(clippy::too_many_arguments)]
+fn snapshot_points(
+    settings: &Settings,
+    auctions: &[Auction],
+    by_items: &HashMap<i64, ItemData>,
+    names_by_id: &HashMap<i64, String>,
     realm: i64,
+    realm_name: Option<&str>,
     ah: i64,
-) -> Result<AuctionList> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::AUTHORIZATION, access_token);
-    headers.insert(
-        "Battlenet-Namespace",
-        header::HeaderValue::from_str(&format!("dynamic-classic-{}", settings.battle_net.region))?,
+    stamps: Timestamps,
+    parse_time: Option<Duration>,
+) -> Result<Vec<DataPoint>> {
+    let mut points = build_points(
+        settings,
+        by_items,
+        names_by_id,
+        realm,
+        realm_name,
+        ah,
+        stamps,
+    )?;
+    points.extend(build_group_points(settings, by_items, realm, ah, stamps)?);
+    if let Some(summary) =
+        build_summary_point(settings, auctions, realm, ah, stamps, parse_time, None)?
+    {
+        points.push(summary);
+    }
+    Ok(points)
+}
+
+/// Builds a `raw_auctions` point for every auction as it was listed, tagged with its auction id
+/// so that each one is its own series.
+fn raw_auction_points(
+    settings: &Settings,
+    auctions: &[Auction],
+    realm: i64,
+    ah: i64,
+    stamps: Timestamps,
+) -> Result<Vec<DataPoint>> {
+    let timestamp = settings.influxdb.timestamp("raw_auctions", stamps);
+    let mut points = Vec::with_capacity(auctions.len());
+    for auction in auctions {
+        let mut point = DataPoint::builder("raw_auctions")
+            .timestamp(timestamp)
+            .tag("auction_id", auction.id.to_string())
+            .tag("item_id", auction.item.id.to_string())
+            .tag("realm_id", realm.to_string())
+            .tag("ah_id", ah.to_string())
+            .field("bid", auction.bid)
+            .field("buyout", auction.buyout)
+            .field("quantity", auction.quantity)
+            .field("time_left", auction.time_left.clone());
+        if let Some(rand) = auction.item.rand {
+            point = point.field("rand", rand);
+        }
+        if let Some(seed) = auction.item.seed {
+            point = point.field("seed", seed);
+        }
+        if let Some(datacenter) = settings.datacenter(realm) {
+            point = point.tag("datacenter", datacenter);
+        }
+        for (key, value) in &settings.influxdb.extra_tags {
+            point = point.tag(key, value);
+        }
+        points.push(point.build()?);
+    }
+    Ok(points)
+}
+
+/// Adds a price field in copper, plus whichever other representations of it are configured.
+fn price_field(
+    influxdb: &InfluxdbSettings,
+    point: DataPointBuilder,
+    name: &str,
+    copper: f64,
+) -> DataPointBuilder {
+    let mut point = match influxdb.price_type {
+        PriceType::Float => point.field(name, copper),
+        PriceType::Integer => point.field(name, copper.round() as i64),
+    };
+    if influxdb.price_in_gold {
+        let mut gold = copper / COPPER_PER_GOLD;
+        if let Some(precision) = influxdb.gold_precision {
+            let scale = 10f64.powi(precision as i32);
+            gold = (gold * scale).round() / scale;
+        }
+        point = point.field(format!("{}_gold", name), gold);
+    }
+    if influxdb.price_denominations {
+        // Per unit prices can have fractions of a copper, which the game never shows.
+        let copper = copper.round() as i64;
+        point = point
+            .field(format!("{}_g", name), copper / 10_000)
+            .field(format!("{}_s", name), copper / 100 % 100)
+            .field(format!("{}_c", name), copper % 100);
+    }
+    point
+}
+
+/// Builds a `group_summary` point for each configured item group seen in this auction house.
+fn build_group_points(
+    settings: &Settings,
+    by_items: &HashMap<i64, ItemData>,
+    realm: i64,
+    ah: i64,
+    stamps: Timestamps,
+) -> Result<Vec<DataPoint>> {
+    let mut points = vec![];
+    for group in &settings.groups {
+        let mut data = ItemData::default();
+        for item in group.items.iter().filter_map(|id| by_items.get(id)) {
+            data.merge(item);
+        }
+        if data.auctions == 0 {
+            continue;
+        }
+
+        let mut point = DataPoint::builder("group_summary")
+            .timestamp(settings.influxdb.timestamp("group_summary", stamps))
+            .tag("group", group.name.as_str())
+            .tag("realm_id", realm.to_string())
+            .tag("ah_id", ah.to_string())
+            .field("count", data.auctions)
+            .field("total_items", data.total_items)
+            .field("total_buyout", data.total_buyout);
+        point = price_field(
+            &settings.influxdb,
+            point,
+            "min_buyout",
+            data.floor_buyout(settings.min_trim_percent)
+                .map_or(0.0, |price| price.per_unit()),
+        );
+        if let Some(price) = data.max_buyout {
+            point = price_field(&settings.influxdb, point, "max_buyout", price.per_unit());
+        }
+        if let Some(datacenter) = settings.datacenter(realm) {
+            point = point.tag("datacenter", datacenter);
+        }
+
+        for (key, value) in &settings.influxdb.extra_tags {
+            point = point.tag(key, value);
+        }
+
+        points.push(point.build()?);
+    }
+    Ok(points)
+}
+
+/// Builds the per auction house `realm_summary` point, if anything is configured to go on it.
+/// `totals` adds the count, distinct items and total buyout of those items, for
+/// `--summary-only`.
+fn build_summary_point(
+    settings: &Settings,
+    auctions: &[Auction],
+    realm: i64,
+    ah: i64,
+    stamps: Timestamps,
+    parse_time: Option<Duration>,
+    totals: Option<&HashMap<i64, ItemData>>,
+) -> Result<Option<DataPoint>> {
+    let influxdb = &settings.influxdb;
+    let mut point = DataPoint::builder("realm_summary")
+        .timestamp(influxdb.timestamp("realm_summary", stamps))
+        .tag("realm_id", realm.to_string())
+        .tag("ah_id", ah.to_string());
+    let mut has_fields = false;
+
+    if influxdb.auction_id_range {
+        if let (Some(min_id), Some(max_id)) = (
+            auctions.iter().map(|auction| auction.id).min(),
+            auctions.iter().map(|auction| auction.id).max(),
+        ) {
+            point = point
+                .field("min_auction_id", min_id)
+                .field("max_auction_id", max_id);
+            has_fields = true;
+        }
+    }
+    if let (true, Some(parse_time)) = (influxdb.parse_time, parse_time) {
+        point = point.field("parse_seconds", parse_time.as_secs_f64());
+        has_fields = true;
+    }
+    if influxdb.last_snapshot {
+        point = point.field("last_snapshot", stamps.snapshot / 1_000_000_000);
+        has_fields = true;
+    }
+    if let Some(by_items) = totals {
+        point = point
+            .field(
+                "count",
+                by_items.values().map(|data| data.auctions).sum::<i64>(),
+            )
+            .field("items", by_items.len() as i64)
+            .field(
+                "total_buyout",
+                by_items
+                    .values()
+                    .fold(0i64, |total, data| total.saturating_add(data.total_buyout)),
+            );
+        has_fields = true;
+    }
+    if !has_fields {
+        return Ok(None);
+    }
+    if let Some(datacenter) = settings.datacenter(realm) {
+        point = point.tag("datacenter", datacenter);
+    }
+
+    for (key, value) in &influxdb.extra_tags {
+        point = point.tag(key, value);
+    }
+
+    Ok(Some(point.build()?))
+}
+
+/// Builds a `new_items` point for each item seen for the first time, on the auction house it
+/// was first seen on, with its count and price there.
+fn build_new_item_points(
+    settings: &Settings,
+    new_items: &[i64],
+    by_items: &HashMap<i64, ItemData>,
+    names_by_id: &HashMap<i64, String>,
+    realm: i64,
+    ah: i64,
+    stamps: Timestamps,
+) -> Result<Vec<DataPoint>> {
+    let influxdb = &settings.influxdb;
+    let timestamp = influxdb.timestamp("new_items", stamps);
+    let mut points = vec![];
+    for id in new_items {
+        let data = match by_items.get(id) {
+            Some(data) => data,
+            None => continue,
+        };
+        let mut point = DataPoint::builder("new_items")
+            .timestamp(timestamp)
+            .tag("item_id", id.to_string())
+            .tag("realm_id", realm.to_string())
+            .tag("ah_id", ah.to_string())
+            .field("count", data.auctions);
+        if let Some(name) = names_by_id.get(id) {
+            point = point.tag("item_name", name.as_str());
+        }
+        // Trimmed the same as on `auctions`, so the two agree.
+        if let Some(price) = data.floor_buyout(settings.min_trim_percent) {
+            point = price_field(influxdb, point, "min_buyout", price.per_unit());
+        }
+        if let Some(datacenter) = settings.datacenter(realm) {
+            point = point.tag("datacenter", datacenter);
+        }
+        for (key, value) in &influxdb.extra_tags {
+            point = point.tag(key, value);
+        }
+        points.push(point.build()?);
+    }
+    Ok(points)
+}
+
+/// Builds a `realm_info` point for each realm in a connected realm.
+async fn build_realm_info_points(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+) -> Result<Vec<DataPoint>> {
+    let link = connected_realm_link(settings, realm);
+    let connected_realm = get_connected_realm(settings, access_token, link).await?;
+    let timestamp = settings
+        .influxdb
+        .timestamp("realm_info", Timestamps::now()?);
+
+    let mut points = vec![];
+    for member in &connected_realm.realms {
+        let mut point = DataPoint::builder("realm_info")
+            .timestamp(timestamp)
+            .tag("realm_id", realm.to_string())
+            .field("name", member.name.clone());
+        if let Some(slug) = &member.slug {
+            point = point.tag("realm_slug", slug.as_str());
+        }
+        if let Some(region) = &member.region {
+            point = point.field("region", region.name.clone());
+        }
+        if let Some(timezone) = &member.timezone {
+            point = point.field("timezone", timezone.clone());
+        }
+        if let Some(population) = &connected_realm.population {
+            point = point.field("population", population.kind.clone());
+        }
+        if let Some(datacenter) = settings.datacenter(realm) {
+            point = point.tag("datacenter", datacenter);
+        }
+
+        for (key, value) in &settings.influxdb.extra_tags {
+            point = point.tag(key, value);
+        }
+
+        points.push(point.build()?);
+    }
+    Ok(points)
+}
+
+fn unix_nanos() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as i64)
+}
+
+const NANOS_PER_HOUR: i64 = 3_600_000_000_000;
+
+/// The times a batch of points could be stamped with, picked between by each measurement's
+/// [`TimestampStrategy`]. Both are nanoseconds since the unix epoch.
+#[derive(Debug, Clone, Copy)]
+struct Timestamps {
+    /// When the data was taken, as far as we know.
+    snapshot: i64,
+    /// When we fetched it.
+    now: i64,
+}
+
+impl Timestamps {
+    /// For data that doesn't say when it was taken.
+    fn now() -> Result<Self> {
+        let now = unix_nanos()?;
+        Ok(Timestamps { snapshot: now, now })
+    }
+}
+
+/// Parses an HTTP date such as `Wed, 21 Oct 2015 07:28:00 GMT` into seconds since the unix
+/// epoch.
+fn parse_http_date(date: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = date.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // Days since the epoch from a civil date, counting years from March so leap days come last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+}
+
+/// Reads item names from the built-in item list, taking each name from the first of
+/// `columns` that isn't empty. Items with no name in any of them are left out, so they're
+/// only identified by id.
+fn read_names_by_id(columns: &[String]) -> Result<HashMap<i64, String>> {
+    let mut result = HashMap::new();
+    if ITEM_NAMES.is_empty() {
+        eprintln!(
+            "Built without src/itemsparse.csv, so items are only named if itemcache looks them up"
+        );
+        return Ok(result);
+    }
+    // Flexible so a row with a stray extra or missing column still gives us its name.
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(GzDecoder::new(ITEM_NAMES));
+    let headers = reader
+        .headers()
+        .context("Couldn't read the item name list's header")?;
+    let indexes = columns
+        .iter()
+        .map(|column| {
+            headers
+                .iter()
+                .position(|header| header == column)
+                .ok_or_else(|| anyhow!("The item name list has no column called {}", column))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut failed = 0;
+
+    for record in reader.records() {
+        match record {
+            Ok(record) => match record.get(0).and_then(|s| i64::from_str(s.trim()).ok()) {
+                Some(id) => {
+                    let name = indexes
+                        .iter()
+                        .filter_map(|index| record.get(*index))
+                        .find(|name| !name.is_empty());
+                    if let Some(name) = name {
+                        result.insert(id, name.to_string());
+                    }
+                }
+                None => failed += 1,
+            },
+            Err(_) => failed += 1,
+        }
+    }
+
+    if failed > 0 {
+        eprintln!(
+            "Couldn't read {} rows of the item name list, those items won't have names",
+            failed
+        );
+    }
+
+    Ok(result)
+}
+
+/// What we remember between runs about each auction house's latest snapshot.
+struct StateFile {
+    path: PathBuf,
+    auction_houses: BTreeMap<String, SnapshotState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SnapshotState {
+    #[serde(default)]
+    etag: Option<String>,
+    /// The realm's auction house index `Last-Modified` when this auction house was last updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_modified: Option<String>,
+    /// The last `min_buyout` of each item with an alert, to tell when it crosses a threshold.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    alert_prices: BTreeMap<i64, f64>,
+    /// How many runs in a row this auction house has failed in.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    failures: u32,
+    /// How many more runs to skip this auction house for, having failed too often.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    quarantined_runs: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl StateFile {
+    fn load(path: &Path) -> Result<Self> {
+        let auction_houses = match File::open(path) {
+            Ok(file) => serde_json::from_reader(file)
+                .with_context(|| format!("Couldn't parse state file {:?}", path))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Couldn't open state file {:?}", path))
+            }
+        };
+
+        Ok(StateFile {
+            path: path.to_path_buf(),
+            auction_houses,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(&self.path)?, &self.auction_houses)?;
+        Ok(())
+    }
+
+    fn key(realm: i64, ah: i64) -> String {
+        format!("{}/{}", realm, ah)
+    }
+
+    fn etag(&self, realm: i64, ah: i64) -> Option<&str> {
+        self.auction_houses
+            .get(&StateFile::key(realm, ah))
+            .and_then(|state| state.etag.as_deref())
+    }
+
+    fn set_etag(&mut self, realm: i64, ah: i64, etag: String) {
+        self.auction_houses
+            .entry(StateFile::key(realm, ah))
+            .or_default()
+            .etag = Some(etag);
+    }
+
+    fn index_modified(&self, realm: i64, ah: i64) -> Option<&str> {
+        self.auction_houses
+            .get(&StateFile::key(realm, ah))
+            .and_then(|state| state.index_modified.as_deref())
+    }
+
+    fn set_index_modified(&mut self, realm: i64, ah: i64, modified: String) {
+        self.auction_houses
+            .entry(StateFile::key(realm, ah))
+            .or_default()
+            .index_modified = Some(modified);
+    }
+
+    fn alert_price(&self, realm: i64, ah: i64, item: i64) -> Option<f64> {
+        self.auction_houses
+            .get(&StateFile::key(realm, ah))
+            .and_then(|state| state.alert_prices.get(&item))
+            .copied()
+    }
+
+    fn set_alert_price(&mut self, realm: i64, ah: i64, item: i64, price: f64) {
+        self.auction_houses
+            .entry(StateFile::key(realm, ah))
+            .or_default()
+            .alert_prices
+            .insert(item, price);
+    }
+
+    /// If the auction house is quarantined, counts off this run and says how many more
+    /// it'll be skipped for.
+    fn skip_quarantined(&mut self, realm: i64, ah: i64) -> Option<u32> {
+        let state = self.auction_houses.get_mut(&StateFile::key(realm, ah))?;
+        if state.quarantined_runs == 0 {
+            return None;
+        }
+        state.quarantined_runs -= 1;
+        Some(state.quarantined_runs)
+    }
+
+    /// Counts a failed run, and quarantines the auction house if it's failed too many in a
+    /// row. Returns how many runs it's now skipped for, if any.
+    fn add_failure(&mut self, realm: i64, ah: i64, quarantine: &QuarantineSettings) -> Option<u32> {
+        let state = self
+            .auction_houses
+            .entry(StateFile::key(realm, ah))
+            .or_default();
+        state.failures = state.failures.saturating_add(1);
+        let runs = quarantine.runs_to_skip(state.failures)?;
+        state.quarantined_runs = runs;
+        Some(runs)
+    }
+
+    /// Forgets about past failures after a successful run, saying whether there were enough
+    /// of them that the auction house had been quarantined.
+    fn clear_failures(&mut self, realm: i64, ah: i64, quarantine: &QuarantineSettings) -> bool {
+        match self.auction_houses.get_mut(&StateFile::key(realm, ah)) {
+            Some(state) => {
+                let failures = std::mem::take(&mut state.failures);
+                quarantine.runs_to_skip(failures).is_some()
+            }
+            None => false,
+        }
+    }
+}
+
+/// Every item id seen on any auction house, kept on disk to tell when one turns up for the
+/// first time.
+struct SeenItems {
+    path: PathBuf,
+    ids: BTreeSet<i64>,
+    /// There was no file yet, so everything counts as already seen rather than new.
+    seeding: bool,
+}
+
+impl SeenItems {
+    fn load(path: &Path) -> Result<Self> {
+        let (ids, seeding) = match File::open(path) {
+            Ok(file) => (
+                serde_json::from_reader(file)
+                    .with_context(|| format!("Couldn't parse seen items {:?}", path))?,
+                false,
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => (BTreeSet::new(), true),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Couldn't open seen items {:?}", path))
+            }
+        };
+
+        Ok(SeenItems {
+            path: path.to_path_buf(),
+            ids,
+            seeding,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        serde_json::to_writer(File::create(&self.path)?, &self.ids)?;
+        Ok(())
+    }
+
+    /// Remembers these items, returning the ones that hadn't been seen before. On the first
+    /// run nothing is new, or every item on the market would be.
+    fn add(&mut self, ids: impl Iterator<Item = i64>) -> Vec<i64> {
+        let mut new = ids.filter(|id| self.ids.insert(*id)).collect::<Vec<_>>();
+        if self.seeding {
+            new.clear();
+        }
+        new.sort_unstable();
+        new
+    }
+}
+
+/// Names for items that itemsparse.csv doesn't know about, looked up from battle.net
+/// and remembered on disk so each new item only costs one request.
+struct ItemNameCache {
+    path: PathBuf,
+    names: BTreeMap<i64, String>,
+    lookups_left: usize,
+    failed: HashSet<i64>,
+}
+
+impl ItemNameCache {
+    fn load(settings: &ItemCacheSettings) -> Result<Self> {
+        let names = match File::open(&settings.path) {
+            Ok(file) => serde_json::from_reader(file)
+                .with_context(|| format!("Couldn't parse item name cache {:?}", settings.path))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Couldn't open item name cache {:?}", settings.path))
+            }
+        };
+
+        Ok(ItemNameCache {
+            path: settings.path.clone(),
+            names,
+            lookups_left: settings.max_lookups,
+            failed: HashSet::new(),
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(&self.path)?, &self.names)?;
+        Ok(())
+    }
+
+    /// Looks up any items in this snapshot that we don't have a name for yet.
+    /// Failures are only logged, as a missing name shouldn't stop prices being written.
+    async fn name_unknown_items(
+        &mut self,
+        settings: &Settings,
+        access_token: HeaderValue,
+        names_by_id: &mut HashMap<i64, String>,
+        by_items: &HashMap<i64, ItemData>,
+    ) {
+        let unknown = by_items
+            .keys()
+            .filter(|id| !names_by_id.contains_key(*id) && !self.failed.contains(*id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for id in unknown {
+            if self.lookups_left == 0 {
+                eprintln!("Reached the item name lookup limit, some items will stay unnamed");
+                return;
+            }
+            self.lookups_left -= 1;
+
+            match get_item(settings, access_token.clone(), id).await {
+                Ok(item) => {
+                    names_by_id.insert(id, item.name.clone());
+                    self.names.insert(id, item.name);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't look up name of item {}: {:#}", id, e);
+                    self.failed.insert(id);
+                }
+            }
+            tokio::time::sleep(ITEM_LOOKUP_DELAY).await;
+        }
+    }
+}
+
+/// Waits until we're allowed to send another request to battle.net.
+async fn throttle(settings: &BlizzardSettings) {
+    if settings.rate_limit.is_some() || settings.rate_limit_hourly.is_some() {
+        RATE_LIMITER
+            .get_or_init(|| RateLimiter::new(settings.rate_limit, settings.rate_limit_hourly))
+            .acquire()
+            .await;
+    }
+}
+
+/// Fetches every auction on an auction house. If `etag` is given and still matches the
+/// latest snapshot, returns `None` rather than downloading the same auctions again.
+async fn get_auctions(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+    ah: i64,
+    etag: Option<&str>,
+) -> Result<Option<AuctionList>> {
+    let client = api_client(settings, access_token, "dynamic")?;
+
+    let backoff = Backoff {
+        retries: settings.battle_net.retries,
+        initial: MAINTENANCE_RETRY_DELAY,
+        max: settings.battle_net.max_retry_delay.map(Duration::from_secs),
+    };
+
+    eprintln!("Requesting auctions for realm {} AH {}...", realm, ah);
+    let mut result = AuctionList::default();
+    let mut url = format!(
+        "{}/data/wow/connected-realm/{}/auctions/{}",
+        settings.battle_net.api_base(),
+        realm,
+        ah
     );
-    let client = ClientBuilder::new().default_headers(headers).build()?;
+    for page in 0..MAX_AUCTION_PAGES {
+        let etag = if page == 0 { etag } else { None };
+        let list = match backoff
+            .retry(
+                |e: &anyhow::Error| e.is::<MaintenanceSnapshot>(),
+                || get_auction_page(settings, &client, &url, etag),
+            )
+            .await?
+        {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        if page == 0 {
+            result.etag = list.etag;
+            result.modified = list.modified;
+        }
+        result.parse_time += list.parse_time;
+        result.auctions.extend(list.auctions);
 
-    println!("Requesting auctions for realm {} AH {}...", realm, ah);
-    Ok(client
-        .get(&format!(
-            "https://{}.api.blizzard.com/data/wow/connected-realm/{}/auctions/{}",
-            settings.battle_net.region, realm, ah
-        ))
+        match list.links.next {
+            Some(next) => url = next.href,
+            None => return Ok(Some(result)),
+        }
+    }
+
+    Err(anyhow!(
+        "Auctions for realm {} AH {} didn't finish after {} pages",
+        realm,
+        ah,
+        MAX_AUCTION_PAGES
+    ))
+}
+
+/// Fetches a single page of auctions, or `None` if `etag` still matches it.
+async fn get_auction_page(
+    settings: &Settings,
+    client: &ApiClient,
+    url: &str,
+    etag: Option<&str>,
+) -> Result<Option<AuctionList>> {
+    throttle(&settings.battle_net).await;
+    let mut request = client.get(url);
+    if let Some(etag) = etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    let response = request
         .send()
         .await
-        .context("Couldn't submit request for auction house data")?
-        .json::<AuctionList>()
+        .context("Couldn't submit request for auction house data")?;
+
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    // Checked before anything else, so an error page is never mistaken for maintenance.
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Battle.net answered {} for auction house data: {}",
+            status,
+            response.text().await.unwrap_or_default().trim()
+        ));
+    }
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let modified = response
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date)
+        .map(|seconds| seconds * 1_000_000_000);
+
+    let body = response
+        .bytes()
+        .await
+        .context("Couldn't read auction house data")?;
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Err(MaintenanceSnapshot::Empty.into());
+    }
+    // Pages can be tens of megabytes, which would hold up every other task on this thread.
+    let started = Instant::now();
+    let parsed = tokio::task::spawn_blocking(move || serde_json::from_slice::<AuctionList>(&body))
         .await
-        .context("Couldn't parse auction house data")?)
+        .context("Couldn't parse auction house data")?;
+    let mut list = match parsed {
+        Ok(list) => list,
+        Err(e) if e.is_eof() => return Err(e).context(MaintenanceSnapshot::CutOff),
+        Err(e) => return Err(e).context("Couldn't parse auction house data"),
+    };
+    list.etag = etag;
+    list.modified = modified;
+    list.parse_time = started.elapsed();
+    Ok(Some(list))
 }
 
 async fn get_connected_realms(
     settings: &Settings,
     access_token: HeaderValue,
 ) -> Result<ConnectedRealmList> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::AUTHORIZATION, access_token);
-    headers.insert(
-        "Battlenet-Namespace",
-        header::HeaderValue::from_str(&format!("dynamic-classic-{}", settings.battle_net.region))?,
-    );
-    let client = ClientBuilder::new().default_headers(headers).build()?;
+    let client = api_client(settings, access_token, "dynamic")?;
 
+    throttle(&settings.battle_net).await;
     Ok(client
         .get(&format!(
-            "https://{}.api.blizzard.com/data/wow/connected-realm/index",
-            settings.battle_net.region
+            "{}/data/wow/connected-realm/index",
+            settings.battle_net.api_base()
         ))
         .send()
         .await
@@ -266,19 +4160,24 @@ async fn get_connected_realms(
         .context("Couldn't parse connected realm list")?)
 }
 
+fn connected_realm_link(settings: &Settings, realm: i64) -> ConnectedRealmLink {
+    ConnectedRealmLink {
+        href: format!(
+            "{}/data/wow/connected-realm/{}",
+            settings.battle_net.api_base(),
+            realm
+        ),
+    }
+}
+
 async fn get_connected_realm(
     settings: &Settings,
     access_token: HeaderValue,
     link: ConnectedRealmLink,
 ) -> Result<ConnectedRealm> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::AUTHORIZATION, access_token);
-    headers.insert(
-        "Battlenet-Namespace",
-        header::HeaderValue::from_str(&format!("dynamic-classic-{}", settings.battle_net.region))?,
-    );
-    let client = ClientBuilder::new().default_headers(headers).build()?;
+    let client = api_client(settings, access_token, "dynamic")?;
 
+    throttle(&settings.battle_net).await;
     Ok(client
         .get(link.href)
         .query(&[("locale", "en_US")])
@@ -290,23 +4189,72 @@ async fn get_connected_realm(
         .context("Couldn't parse connected realm")?)
 }
 
+async fn get_realm(
+    settings: &Settings,
+    access_token: HeaderValue,
+    slug: &str,
+) -> Result<RealmDetails> {
+    let client = api_client(settings, access_token, "dynamic")?;
+
+    throttle(&settings.battle_net).await;
+    Ok(client
+        .get(&format!(
+            "{}/data/wow/realm/{}",
+            settings.battle_net.api_base(),
+            slug,
+        ))
+        .query(&[("locale", "en_US")])
+        .send()
+        .await
+        .context("Couldn't submit request for realm")?
+        .json::<RealmDetails>()
+        .await
+        .context("Couldn't parse realm")?)
+}
+
+/// When a realm's auction house index last changed, which is much cheaper to ask than
+/// downloading the auctions themselves. `None` if battle.net didn't say.
+async fn get_auction_index_modified(
+    settings: &Settings,
+    access_token: HeaderValue,
+    realm: i64,
+) -> Result<Option<String>> {
+    let client = api_client(settings, access_token, "dynamic")?;
+
+    throttle(&settings.battle_net).await;
+    let response = client
+        .get(&format!(
+            "{}/data/wow/connected-realm/{}/auctions/index",
+            settings.battle_net.api_base(),
+            realm,
+        ))
+        .query(&[("locale", "en_US")])
+        .send()
+        .await
+        .context("Couldn't submit request for auction house index")?
+        .error_for_status()
+        .context("Couldn't fetch auction house index")?;
+
+    Ok(response
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from))
+}
+
 async fn get_auction_houses(
     settings: &Settings,
     access_token: HeaderValue,
     realm: i64,
 ) -> Result<AuctionHouseList> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(header::AUTHORIZATION, access_token);
-    headers.insert(
-        "Battlenet-Namespace",
-        header::HeaderValue::from_str(&format!("dynamic-classic-{}", settings.battle_net.region))?,
-    );
-    let client = ClientBuilder::new().default_headers(headers).build()?;
+    let client = api_client(settings, access_token, "dynamic")?;
 
+    throttle(&settings.battle_net).await;
     Ok(client
         .get(&format!(
-            "https://{}.api.blizzard.com/data/wow/connected-realm/{}/auctions/index",
-            settings.battle_net.region, realm,
+            "{}/data/wow/connected-realm/{}/auctions/index",
+            settings.battle_net.api_base(),
+            realm,
         ))
         .query(&[("locale", "en_US")])
         .send()
@@ -317,19 +4265,127 @@ async fn get_auction_houses(
         .context("Couldn't parse auction house index")?)
 }
 
-async fn get_access_token(settings: &BlizzardSettings) -> Result<header::HeaderValue> {
-    let client = BasicClient::new(
-        settings.client_id.clone(),
-        Some(settings.client_secret.clone()),
-        AuthUrl::new("http://localhost:8080".to_string())?,
-        Some(TokenUrl::new("https://oauth.battle.net/token".to_string())?),
+async fn get_item(
+    settings: &Settings,
+    access_token: HeaderValue,
+    item: i64,
+) -> Result<ItemDetails> {
+    let client = api_client(settings, access_token, "static")?;
+
+    throttle(&settings.battle_net).await;
+    Ok(client
+        .get(&format!(
+            "{}/data/wow/item/{}",
+            settings.battle_net.api_base(),
+            item,
+        ))
+        .query(&[("locale", "en_US")])
+        .send()
+        .await
+        .context("Couldn't submit request for item")?
+        .json::<ItemDetails>()
+        .await
+        .context("Couldn't parse item")?)
+}
+
+/// Starts building an HTTP client for talking to Blizzard, with any configured proxy applied.
+fn http_client_builder(settings: &BlizzardSettings) -> Result<ClientBuilder> {
+    let mut builder = ClientBuilder::new();
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid battle.net proxy")?);
+    }
+    Ok(builder)
+}
+
+/// A client that sends our access token and the given namespace (`dynamic` or `static`)
+/// with every request.
+fn api_client(
+    settings: &Settings,
+    access_token: HeaderValue,
+    namespace: &str,
+) -> Result<ApiClient> {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, access_token);
+    headers.insert(
+        "Battlenet-Namespace",
+        header::HeaderValue::from_str(&format!(
+            "{}-classic-{}",
+            namespace, settings.battle_net.region
+        ))?,
     );
 
-    println!("Authenticating...");
-    let result = client
-        .exchange_client_credentials()
-        .request_async(async_http_client)
+    let client = match HTTP_CLIENT.get() {
+        Some(client) => client,
+        None => {
+            let settings = &settings.battle_net;
+            let mut builder = http_client_builder(settings)?;
+            if settings.http2 {
+                builder = builder.http2_prior_knowledge();
+            }
+            if let Some(seconds) = settings.pool_idle_timeout {
+                builder = builder.pool_idle_timeout(Duration::from_secs(seconds));
+            }
+            if let Some(max) = settings.pool_max_idle {
+                builder = builder.pool_max_idle_per_host(max);
+            }
+            let client = builder.build()?;
+            HTTP_CLIENT.get_or_init(|| client)
+        }
+    };
+
+    Ok(ApiClient {
+        client: client.clone(),
+        headers,
+    })
+}
+
+/// Sends requests to the battle.net API through the shared client.
+struct ApiClient {
+    client: reqwest::Client,
+    headers: header::HeaderMap,
+}
+
+impl ApiClient {
+    fn get(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client.get(url).headers(self.headers.clone())
+    }
+}
+
+/// Sends an OAuth request through our own client, so it goes via the same proxy as
+/// everything else.
+async fn oauth_http_client(
+    client: reqwest::Client,
+    request: HttpRequest,
+) -> Result<HttpResponse, reqwest::Error> {
+    let response = client
+        .request(request.method, request.url.as_str())
+        .headers(request.headers)
+        .body(request.body)
+        .send()
         .await?;
+    let status_code = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?.to_vec();
+
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body,
+    })
+}
+
+/// Whether a failed token exchange is worth trying again. An error response from the token
+/// endpoint means it didn't like our credentials, which won't change by asking again; anything
+/// else (no connection, an error page instead of JSON) may well be temporary.
+fn is_transient_token_error<RE: std::error::Error + 'static>(
+    error: &RequestTokenError<RE, BasicErrorResponse>,
+) -> bool {
+    !matches!(error, RequestTokenError::ServerResponse(_))
+}
+
+async fn get_access_token(settings: &BlizzardSettings) -> Result<header::HeaderValue> {
+    eprintln!("Authenticating...");
+    let result = request_token(settings).await?;
     let mut value = header::HeaderValue::from_str(&format!(
         "{:?} {}",
         result.token_type(),
@@ -340,6 +4396,58 @@ async fn get_access_token(settings: &BlizzardSettings) -> Result<header::HeaderV
     Ok(value)
 }
 
+/// Exchanges our credentials, or refresh token, for an access token.
+async fn request_token(settings: &BlizzardSettings) -> Result<BasicTokenResponse> {
+    let client = BasicClient::new(
+        settings.client_id.clone(),
+        Some(settings.client_secret.clone()),
+        AuthUrl::new("http://localhost:8080".to_string())?,
+        Some(TokenUrl::new(settings.token_url())?),
+    );
+
+    // Following redirects would let the token endpoint send our credentials somewhere else.
+    let http_client = http_client_builder(settings)?
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    let backoff = Backoff {
+        retries: settings.retries,
+        initial: Duration::from_secs(1),
+        max: settings.max_retry_delay.map(Duration::from_secs),
+    };
+
+    let result = match &settings.refresh_token_file {
+        Some(path) => {
+            let refresh_token = fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read refresh token from {:?}", path))?;
+            let refresh_token = RefreshToken::new(refresh_token.trim().to_string());
+            let result = backoff
+                .retry(is_transient_token_error, || {
+                    client
+                        .exchange_refresh_token(&refresh_token)
+                        .request_async(|request| oauth_http_client(http_client.clone(), request))
+                })
+                .await?;
+            // The old token may stop working now, so the new one has to be kept.
+            if let Some(rotated) = result.refresh_token() {
+                fs::write(path, rotated.secret())
+                    .with_context(|| format!("Couldn't save refresh token to {:?}", path))?;
+            }
+            result
+        }
+        None => {
+            backoff
+                .retry(is_transient_token_error, || {
+                    client
+                        .exchange_client_credentials()
+                        .request_async(|request| oauth_http_client(http_client.clone(), request))
+                })
+                .await?
+        }
+    };
+    Ok(result)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct AuctionHouseList {
     pub auctions: Vec<AuctionHouse>,
@@ -361,15 +4469,69 @@ struct ConnectedRealmLink {
     pub href: String,
 }
 
+impl ConnectedRealmLink {
+    /// The connected realm id at the end of the link, such as 4728 in
+    /// `https://us.api.blizzard.com/data/wow/connected-realm/4728?namespace=...`.
+    fn id(&self) -> Option<i64> {
+        self.href
+            .split('?')
+            .next()?
+            .rsplit('/')
+            .next()?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RealmDetails {
+    pub connected_realm: ConnectedRealmLink,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ConnectedRealm {
     pub id: i64,
     pub realms: Vec<Realm>,
+    #[serde(default)]
+    pub status: Option<RealmStatus>,
+    #[serde(default)]
+    pub population: Option<RealmPopulation>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RealmStatus {
+    /// `UP` or `DOWN`.
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RealmPopulation {
+    /// Such as `FULL` or `LOW`.
+    #[serde(rename = "type")]
+    pub kind: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Realm {
     pub name: String,
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub region: Option<RealmRegion>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RealmRegion {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ItemDetails {
+    pub id: i64,
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -389,14 +4551,552 @@ struct Auction {
     pub time_left: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct AuctionList {
     pub auctions: Vec<Auction>,
+    #[serde(rename = "_links", default)]
+    pub links: PageLinks,
+    /// Identifies this snapshot, for asking whether it's changed next time.
+    #[serde(skip)]
+    pub etag: Option<String>,
+    /// When battle.net says this snapshot was taken, in nanoseconds since the unix epoch.
+    #[serde(skip)]
+    pub modified: Option<i64>,
+    /// How long parsing every page took.
+    #[serde(skip)]
+    pub parse_time: Duration,
+}
+
+/// An auction list that came back empty or cut off part way through, with a successful
+/// status. Battle.net sends these during maintenance, instead of an error.
+#[derive(Debug)]
+enum MaintenanceSnapshot {
+    Empty,
+    CutOff,
+}
+
+impl Display for MaintenanceSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MaintenanceSnapshot::Empty => write!(f, "battle.net sent an empty auction list"),
+            MaintenanceSnapshot::CutOff => {
+                write!(
+                    f,
+                    "battle.net sent an auction list that was cut off part way through"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MaintenanceSnapshot {}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PageLinks {
+    pub next: Option<Link>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Link {
+    pub href: String,
 }
 
 #[derive(Debug, Default)]
 struct ItemData {
     auctions: i64,
     total_items: i64,
-    min_buyout: i64,
+    /// Sum of every buyout, in copper.
+    total_buyout: i64,
+    min_buyout: Option<UnitPrice>,
+    max_buyout: Option<UnitPrice>,
+    /// Every buyout price, cheapest first.
+    prices: Vec<UnitPrice>,
+    /// Sum of bid / buyout over every auction with both, for an average.
+    bid_ratio_total: f64,
+    bid_ratios: i64,
+    /// How many auctions were posted at each (buyout, quantity).
+    listings: HashMap<(i64, i64), i64>,
+    /// Sum of `timeleftminutes` over every auction with a known `time_left`, for an average.
+    time_left_total: f64,
+    time_lefts: i64,
+    /// The same statistics for each (rand, seed) random suffix, when `randomsuffixtags` is on.
+    variants: BTreeMap<(Option<i64>, Option<i64>), ItemData>,
+}
+
+impl ItemData {
+    fn add(&mut self, auction: &Auction, time_left_minutes: Option<f64>) {
+        self.count(auction);
+        if let Some(minutes) = time_left_minutes {
+            self.add_time_left(minutes);
+        }
+        if auction.buyout > 0 && auction.quantity > 0 {
+            self.total_buyout = self.total_buyout.saturating_add(auction.buyout);
+            let price = UnitPrice {
+                total: auction.buyout,
+                quantity: auction.quantity,
+            };
+            self.add_buyout(price);
+            self.prices.push(price);
+            *self
+                .listings
+                .entry((auction.buyout, auction.quantity))
+                .or_default() += 1;
+
+            if auction.bid > 0 {
+                self.bid_ratio_total += auction.bid as f64 / auction.buyout as f64;
+                self.bid_ratios += 1;
+            }
+        }
+    }
+
+    fn add_time_left(&mut self, minutes: f64) {
+        self.time_left_total += minutes;
+        self.time_lefts += 1;
+    }
+
+    /// Counts an auction without letting its price affect anything.
+    fn count(&mut self, auction: &Auction) {
+        self.auctions += 1;
+        self.total_items = self.total_items.saturating_add(auction.quantity);
+    }
+
+    /// Folds another set of statistics into this one, for totals across several items.
+    fn merge(&mut self, other: &ItemData) {
+        self.auctions += other.auctions;
+        self.total_items = self.total_items.saturating_add(other.total_items);
+        self.total_buyout = self.total_buyout.saturating_add(other.total_buyout);
+        if let Some(price) = other.min_buyout {
+            self.add_buyout(price);
+        }
+        if let Some(price) = other.max_buyout {
+            self.add_buyout(price);
+        }
+        self.prices.extend_from_slice(&other.prices);
+        self.prices.sort();
+        self.bid_ratio_total += other.bid_ratio_total;
+        self.bid_ratios += other.bid_ratios;
+        for (listing, count) in &other.listings {
+            *self.listings.entry(*listing).or_default() += count;
+        }
+        self.time_left_total += other.time_left_total;
+        self.time_lefts += other.time_lefts;
+        for (suffix, variant) in &other.variants {
+            self.variants.entry(*suffix).or_default().merge(variant);
+        }
+    }
+
+    /// How many identical (buyout, quantity) listings were posted at least `min_size` times.
+    fn likely_walls(&self, min_size: i64) -> i64 {
+        self.listings
+            .values()
+            .filter(|count| **count >= min_size)
+            .count() as i64
+    }
+
+    /// Average starting bid as a fraction of buyout, ignoring auctions without both.
+    fn avg_bid_to_buyout_ratio(&self) -> Option<f64> {
+        if self.bid_ratios > 0 {
+            Some(self.bid_ratio_total / self.bid_ratios as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Average minutes left across auctions, going by `timeleftminutes`.
+    fn avg_time_left_minutes(&self) -> Option<f64> {
+        if self.time_lefts > 0 {
+            Some(self.time_left_total / self.time_lefts as f64)
+        } else {
+            None
+        }
+    }
+
+    /// The cheapest buyout, optionally ignoring a percentage of the cheapest auctions.
+    fn floor_buyout(&self, trim_percent: Option<f64>) -> Option<UnitPrice> {
+        match trim_percent {
+            Some(_) => self.prices.get(self.floor_index(trim_percent)).copied(),
+            None => self.min_buyout,
+        }
+    }
+
+    /// How much more the next cheapest price per unit is than `floor_buyout`, which is the
+    /// room there is to post under it. `None` when there's no next price.
+    fn undercut_gap(&self, trim_percent: Option<f64>) -> Option<f64> {
+        let floor = self.floor_index(trim_percent);
+        let cheapest = self.prices.get(floor)?;
+        let next = self.prices.get(floor + 1)?;
+        Some(next.per_unit() - cheapest.per_unit())
+    }
+
+    /// The middle buyout price per unit, or the average of the two middle ones.
+    fn median_buyout(&self) -> Option<f64> {
+        let middle = self.prices.len() / 2;
+        match self.prices.len() {
+            0 => None,
+            len if len.is_multiple_of(2) => {
+                Some((self.prices[middle - 1].per_unit() + self.prices[middle].per_unit()) / 2.0)
+            }
+            _ => Some(self.prices[middle].per_unit()),
+        }
+    }
+
+    /// How crowded this item's market is, from 0 to 1, as described on
+    /// `CompetitivenessSettings`. `None` when nothing had a buyout.
+    fn competitiveness(
+        &self,
+        weights: &CompetitivenessSettings,
+        trim_percent: Option<f64>,
+    ) -> Option<f64> {
+        let floor = self.floor_buyout(trim_percent)?.per_unit();
+        let median = self.median_buyout()?;
+        let count = self.auctions as f64;
+
+        let listings = count / (count + weights.listings_scale);
+        let undercut_gap = self
+            .undercut_gap(trim_percent)
+            .map_or(0.0, |gap| (1.0 - gap / floor).max(0.0));
+        let spread = (floor / median).min(1.0);
+
+        let total = weights.listings + weights.undercut_gap + weights.spread;
+        Some(
+            (listings * weights.listings
+                + undercut_gap * weights.undercut_gap
+                + spread * weights.spread)
+                / total,
+        )
+    }
+
+    /// How many auctions have a price per unit in each bucket between `boundaries`, which are
+    /// in copper and sorted. A price on a boundary counts towards the bucket above it.
+    fn price_buckets(&self, boundaries: &[i64]) -> Vec<i64> {
+        let mut counts = vec![0; boundaries.len() + 1];
+        for price in &self.prices {
+            let per_unit = price.per_unit();
+            counts[boundaries.partition_point(|boundary| *boundary as f64 <= per_unit)] += 1;
+        }
+        counts
+    }
+
+    /// Where `floor_buyout` is in `prices`, after skipping `trim_percent` of them.
+    fn floor_index(&self, trim_percent: Option<f64>) -> usize {
+        let skip = trim_percent.map_or(0, |percent| {
+            (self.prices.len() as f64 * percent / 100.0).floor() as usize
+        });
+        skip.min(self.prices.len().saturating_sub(1))
+    }
+
+    fn add_buyout(&mut self, price: UnitPrice) {
+        if self.min_buyout.is_none_or(|min| price < min) {
+            self.min_buyout = Some(price);
+        }
+        if self.max_buyout.is_none_or(|max| price > max) {
+            self.max_buyout = Some(price);
+        }
+    }
+}
+
+/// A per-unit price kept as the auction's whole buyout and quantity, so that cheap stacks
+/// don't lose their fractional copper to integer division.
+#[derive(Debug, Clone, Copy)]
+struct UnitPrice {
+    total: i64,
+    quantity: i64,
+}
+
+impl UnitPrice {
+    fn per_unit(&self) -> f64 {
+        self.total as f64 / self.quantity as f64
+    }
+}
+
+impl Ord for UnitPrice {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Cross-multiply rather than divide, so the comparison stays exact.
+        (self.total as i128 * other.quantity as i128)
+            .cmp(&(other.total as i128 * self.quantity as i128))
+    }
+}
+
+impl PartialOrd for UnitPrice {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for UnitPrice {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for UnitPrice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb2::models::WriteDataPoint;
+
+    /// The least config that deserializes, with `extra` top-level settings in front of it.
+    fn settings(extra: &str) -> Settings {
+        let config = format!(
+            "{}\n\
+             [influxdb]\n\
+             host = \"http://localhost:8086\"\n\
+             org = \"org\"\n\
+             token = \"token\"\n\
+             bucket = \"bucket\"\n\
+             [battlenet]\n\
+             region = \"us\"\n\
+             clientid = \"id\"\n\
+             clientsecret = \"secret\"\n",
+            extra
+        );
+        Figment::from(Toml::string(&config))
+            .extract()
+            .expect("test config should deserialize")
+    }
+
+    fn auction(id: i64, item: i64, buyout: i64, quantity: i64) -> Auction {
+        Auction {
+            id,
+            item: Item {
+                id: item,
+                rand: None,
+                seed: None,
+            },
+            bid: 0,
+            buyout,
+            quantity,
+            time_left: "LONG".to_string(),
+        }
+    }
+
+    #[test]
+    fn zero_buyout_is_counted_but_not_priced() {
+        let by_items = aggregate(&settings(""), &[auction(1, 10, 0, 5)]);
+        let data = &by_items[&10];
+        assert_eq!(data.auctions, 1);
+        assert_eq!(data.total_items, 5);
+        assert_eq!(data.total_buyout, 0);
+        assert!(data.min_buyout.is_none());
+        assert!(data.max_buyout.is_none());
+        assert!(data.prices.is_empty());
+    }
+
+    #[test]
+    fn prices_are_per_unit_across_stack_sizes() {
+        let by_items = aggregate(
+            &settings(""),
+            &[auction(1, 10, 100, 1), auction(2, 10, 2000, 200)],
+        );
+        let data = &by_items[&10];
+        assert_eq!(data.auctions, 2);
+        assert_eq!(data.total_items, 201);
+        assert_eq!(data.total_buyout, 2100);
+        assert_eq!(data.min_buyout.unwrap().per_unit(), 10.0);
+        assert_eq!(data.max_buyout.unwrap().per_unit(), 100.0);
+    }
+
+    #[test]
+    fn totals_saturate_instead_of_overflowing() {
+        let by_items = aggregate(
+            &settings(""),
+            &[
+                auction(1, 10, i64::MAX, i64::MAX),
+                auction(2, 10, i64::MAX, i64::MAX),
+            ],
+        );
+        let data = &by_items[&10];
+        assert_eq!(data.total_items, i64::MAX);
+        assert_eq!(data.total_buyout, i64::MAX);
+        assert_eq!(data.min_buyout.unwrap().per_unit(), 1.0);
+    }
+
+    #[test]
+    fn min_buyout_keeps_fractions_of_a_copper() {
+        let by_items = aggregate(&settings(""), &[auction(1, 10, 3, 2)]);
+        assert_eq!(by_items[&10].min_buyout.unwrap().per_unit(), 1.5);
+    }
+
+    #[test]
+    fn unit_prices_order_by_their_fractions() {
+        // Both would be 1 copper each with integer division.
+        let cheaper = UnitPrice {
+            total: 3,
+            quantity: 2,
+        };
+        let dearer = UnitPrice {
+            total: 8,
+            quantity: 5,
+        };
+        assert!(cheaper < dearer);
+        assert_eq!(
+            UnitPrice {
+                total: 6,
+                quantity: 4
+            },
+            cheaper
+        );
+
+        let by_items = aggregate(&settings(""), &[auction(1, 10, 8, 5), auction(2, 10, 3, 2)]);
+        assert_eq!(by_items[&10].min_buyout.unwrap().per_unit(), 1.5);
+        assert_eq!(by_items[&10].max_buyout.unwrap().per_unit(), 1.6);
+    }
+
+    #[test]
+    fn item_points_have_the_canonical_tags() {
+        let mut settings = settings(
+            "specialitems = [10]\n\
+             [datacenters]\n\
+             3 = \"us-west\"\n\
+             [itemtags]\n\
+             10 = { category = \"flask\" }",
+        );
+        settings.influxdb.random_suffix_tags = true;
+        settings
+            .influxdb
+            .extra_tags
+            .insert("environment".to_string(), "test".to_string());
+        let mut suffixed = auction(1, 10, 100, 1);
+        suffixed.item.rand = Some(5);
+        suffixed.item.seed = Some(6);
+        let by_items = aggregate(&settings, &[suffixed]);
+        let names_by_id = HashMap::from([(10, "Flask".to_string())]);
+
+        let points = build_points(
+            &settings,
+            &by_items,
+            &names_by_id,
+            3,
+            Some("Faerlina"),
+            2,
+            Timestamps {
+                snapshot: 0,
+                now: 0,
+            },
+        )
+        .unwrap();
+        assert_eq!(points.len(), 1);
+        let mut line = vec![];
+        points[0].write_data_point_to(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let tags = line
+            .split(' ')
+            .next()
+            .unwrap()
+            .split(',')
+            .skip(1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                "ah_id=2",
+                "category=flask",
+                "datacenter=us-west",
+                "environment=test",
+                "item_id=10",
+                "item_name=Flask",
+                "rand=5",
+                "realm_id=3",
+                "realm_name=Faerlina",
+                "seed=6",
+                "special=true",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_auctions_only_count_once() {
+        let auctions = || {
+            vec![
+                auction(1, 10, 100, 1),
+                auction(1, 10, 50, 5),
+                auction(2, 10, 200, 2),
+            ]
+        };
+        for mode in ["ignore", "warn"] {
+            let settings = settings(&format!("duplicateauctions = \"{}\"", mode));
+            let auctions = dedupe_auctions(&settings, auctions(), 3, 2).unwrap();
+            assert_eq!(
+                auctions
+                    .iter()
+                    .map(|auction| (auction.id, auction.buyout))
+                    .collect::<Vec<_>>(),
+                [(1, 100), (2, 200)],
+                "{}",
+                mode
+            );
+            let data = &aggregate(&settings, &auctions)[&10];
+            assert_eq!(data.auctions, 2, "{}", mode);
+            assert_eq!(data.total_items, 3, "{}", mode);
+        }
+
+        let settings = settings("duplicateauctions = \"error\"");
+        assert!(dedupe_auctions(&settings, auctions(), 3, 2).is_err());
+    }
+
+    /// The example config with every commented out setting uncommented, leaving out sections
+    /// for features this wasn't built with.
+    fn uncommented_example_config() -> String {
+        let is_setting = |line: &str| {
+            line.starts_with('[')
+                || line.split_once(" = ").is_some_and(|(key, _)| {
+                    key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                })
+        };
+        let mut config = String::new();
+        let mut continued = false;
+        let mut skipping = false;
+        for line in EXAMPLE_CONFIG.lines() {
+            let line = if continued {
+                line.trim_start_matches('#')
+            } else if let Some(setting) = line.strip_prefix("# ").filter(|rest| is_setting(rest)) {
+                setting
+            } else if line.starts_with('#') {
+                continue;
+            } else {
+                line
+            };
+            // Arrays split over several lines, such as `fields`.
+            continued = line.trim_end().ends_with(',');
+            if line.starts_with('[') {
+                skipping = match line.trim_matches(|c: char| c == '[' || c == ']') {
+                    "kafka" => !cfg!(feature = "kafka"),
+                    "sentry" => !cfg!(feature = "sentry"),
+                    _ => false,
+                };
+            }
+            if !skipping {
+                config.push_str(line);
+                config.push('\n');
+            }
+        }
+        config
+    }
+
+    #[test]
+    fn example_config_works_as_it_is() {
+        Figment::from(Toml::string(EXAMPLE_CONFIG))
+            .extract::<Settings>()
+            .unwrap();
+    }
+
+    #[test]
+    fn example_config_only_has_real_settings() {
+        let config = uncommented_example_config();
+        let settings = Figment::from(Toml::string(&config))
+            .extract::<Settings>()
+            .unwrap_or_else(|e| panic!("{}\n\n{}", e, config));
+        // Some from each part of the file, to be sure they were really uncommented.
+        assert_eq!(settings.min_trim_percent, Some(5.0));
+        assert_eq!(settings.item_tags.len(), 1);
+        assert!(settings.influxdb.random_suffix_tags);
+        assert_eq!(
+            settings.influxdb.fields.map(|fields| fields.len()),
+            Some(10)
+        );
+        assert_eq!(settings.battle_net.max_retry_delay, Some(60));
+        assert!(settings.discover.is_some());
+        assert_eq!(settings.groups.len(), 1);
+    }
 }
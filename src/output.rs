@@ -0,0 +1,635 @@
+use crate::InfluxdbSettings;
+use anyhow::{anyhow, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use influxdb2::models::{DataPoint, WriteDataPoint};
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Where aggregated auction data is sent.
+pub enum Output {
+    /// As points, usually to InfluxDB.
+    Points(PointWriter),
+    /// As newline delimited JSON on stdout, for piping into other tools.
+    Ndjson,
+    /// As gauges in an OpenMetrics text file, rewritten at the end of each run.
+    OpenMetrics(OpenMetricsFile),
+    /// As JSON messages on a Kafka topic, for streaming pipelines.
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaSink),
+}
+
+impl Output {
+    pub async fn finish(self) -> Result<()> {
+        match self {
+            Output::Points(writer) => writer.finish().await,
+            Output::Ndjson => Ok(()),
+            Output::OpenMetrics(file) => file.save(),
+            // Each batch has already been delivered by the time `publish` returns.
+            #[cfg(feature = "kafka")]
+            Output::Kafka(_) => Ok(()),
+        }
+    }
+}
+
+/// Metrics collected over a run, to be written out in OpenMetrics text format.
+pub struct OpenMetricsFile {
+    path: PathBuf,
+    families: BTreeMap<&'static str, MetricFamily>,
+}
+
+struct MetricFamily {
+    help: &'static str,
+    samples: Vec<String>,
+}
+
+impl OpenMetricsFile {
+    pub fn new(path: &Path) -> Self {
+        OpenMetricsFile {
+            path: path.to_path_buf(),
+            families: BTreeMap::new(),
+        }
+    }
+
+    pub fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, String)],
+        value: f64,
+    ) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.families
+            .entry(name)
+            .or_insert_with(|| MetricFamily {
+                help,
+                samples: vec![],
+            })
+            .samples
+            .push(format!("{}{{{}}} {}", name, labels, value));
+    }
+
+    /// Writes every metric, replacing the file in one go so a scraper never sees half of it.
+    pub fn save(&self) -> Result<()> {
+        let mut body = String::new();
+        for (name, family) in &self.families {
+            body.push_str(&format!("# HELP {} {}\n", name, family.help));
+            body.push_str(&format!("# TYPE {} gauge\n", name));
+            for sample in &family.samples {
+                body.push_str(sample);
+                body.push('\n');
+            }
+        }
+        body.push_str("# EOF\n");
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, body).with_context(|| format!("Couldn't write {:?}", temp))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("Couldn't move metrics into place at {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prints each record as a line of JSON to stdout.
+pub fn write_ndjson<T: Serialize>(records: &[T]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for record in records {
+        serde_json::to_writer(&mut stdout, record)?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// A Kafka topic that records are published to as JSON.
+#[cfg(feature = "kafka")]
+pub struct KafkaSink {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str) -> Result<Self> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()
+            .context("Couldn't create Kafka producer")?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+
+    /// Publishes each record with its key, waiting until every one has been delivered.
+    pub async fn publish<T: Serialize>(&self, records: &[(String, T)]) -> Result<()> {
+        let payloads = records
+            .iter()
+            .map(|(key, record)| Ok((key, serde_json::to_vec(record)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let deliveries = payloads.iter().map(|(key, payload)| {
+            self.producer.send(
+                rdkafka::producer::FutureRecord::to(&self.topic)
+                    .key(key.as_str())
+                    .payload(payload),
+                KAFKA_QUEUE_TIMEOUT,
+            )
+        });
+        for delivery in futures::future::join_all(deliveries).await {
+            delivery.map_err(|(e, _)| e)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long a message may wait for room in the producer's queue before giving up.
+#[cfg(feature = "kafka")]
+const KAFKA_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to ask whether InfluxDB is ready yet, while waiting for it at startup.
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Ends each certificate in a PEM bundle.
+const PEM_CERT_END: &str = "-----END CERTIFICATE-----";
+
+/// Where finished points go once an auction house has been aggregated.
+pub enum PointWriter {
+    /// Write each batch before moving on to the next auction house.
+    Inline(InfluxdbTarget),
+    /// Hand batches to a background task, so fetching the next auction house
+    /// overlaps with writing the previous one.
+    Background {
+        sender: mpsc::Sender<Vec<DataPoint>>,
+        task: Option<JoinHandle<Result<()>>>,
+    },
+    /// Stream points as line protocol to QuestDB's ILP port.
+    Questdb(QuestdbTarget),
+    /// Throw points away, for timing everything but the write itself.
+    Null { discarded: usize },
+}
+
+impl PointWriter {
+    pub fn new(settings: &InfluxdbSettings) -> Result<Self> {
+        if let Some(address) = &settings.questdb {
+            return Ok(PointWriter::Questdb(QuestdbTarget::new(address, settings)));
+        }
+        let target = InfluxdbTarget::new(settings)?;
+
+        match settings.write_queue {
+            Some(capacity) => {
+                // A bounded channel means we stop fetching when the writer falls behind,
+                // rather than holding every auction house in memory.
+                let (sender, mut receiver) = mpsc::channel::<Vec<DataPoint>>(capacity.max(1));
+                let task = tokio::spawn(async move {
+                    while let Some(points) = receiver.recv().await {
+                        target.write(points).await?;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                });
+                Ok(PointWriter::Background {
+                    sender,
+                    task: Some(task),
+                })
+            }
+            None => Ok(PointWriter::Inline(target)),
+        }
+    }
+
+    pub async fn write(&mut self, points: Vec<DataPoint>) -> Result<()> {
+        match self {
+            PointWriter::Inline(target) => {
+                target.write(points).await?;
+            }
+            PointWriter::Background { sender, task } => {
+                if sender.send(points).await.is_err() {
+                    // The writer only hangs up early when a write failed, so report why.
+                    if let Some(task) = task.take() {
+                        task.await??;
+                    }
+                    return Err(anyhow!("Background InfluxDB writer stopped unexpectedly"));
+                }
+            }
+            PointWriter::Questdb(target) => {
+                target.write(points).await?;
+            }
+            PointWriter::Null { discarded } => {
+                *discarded += points.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every dead letter in `dir` to wherever `settings` sends points, removing each
+    /// once it's written. Nothing is queued, and failures aren't saved as dead letters again.
+    pub async fn replay(settings: &InfluxdbSettings, dir: &Path) -> Result<()> {
+        match &settings.questdb {
+            Some(address) => {
+                let mut target = QuestdbTarget::new(address, settings);
+                target.replay(dir).await?;
+                target.finish().await
+            }
+            None => InfluxdbTarget::new(settings)?.replay(dir).await,
+        }
+    }
+
+    /// Waits for any queued points to be written.
+    pub async fn finish(self) -> Result<()> {
+        match self {
+            PointWriter::Inline(_) => {}
+            PointWriter::Questdb(target) => target.finish().await?,
+            PointWriter::Background { sender, task } => {
+                drop(sender);
+                if let Some(task) = task {
+                    task.await??;
+                }
+            }
+            PointWriter::Null { discarded } => {
+                eprintln!("Discarded {} points", discarded);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A bucket in InfluxDB, plus where to put points that couldn't be written to it.
+pub struct InfluxdbTarget {
+    client: influxdb2::Client,
+    org: String,
+    bucket: String,
+    dead_letter: Option<PathBuf>,
+    /// Set when writes are gzipped, which the InfluxDB client can't do, so they're sent
+    /// with our own requests instead.
+    gzip: Option<GzipWriter>,
+}
+
+/// What's needed to send a gzipped write request ourselves.
+struct GzipWriter {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl InfluxdbTarget {
+    pub fn new(settings: &InfluxdbSettings) -> Result<Self> {
+        let http = || {
+            let mut http = influxdb_client_builder(settings)?;
+            if let Some(seconds) = settings.write_timeout {
+                http = http.timeout(Duration::from_secs(seconds));
+            }
+            Ok::<_, anyhow::Error>(http)
+        };
+        let client = influxdb2::ClientBuilder::with_builder(
+            http()?,
+            &settings.host,
+            &settings.org,
+            settings.token.secret(),
+        )
+        .build()
+        .context("Couldn't create InfluxDB client")?;
+
+        let gzip = if settings.gzip {
+            Some(GzipWriter {
+                http: http()?.build().context("Couldn't create InfluxDB client")?,
+                url: format!("{}/api/v2/write", settings.host.trim_end_matches('/')),
+                token: settings.token.secret().clone(),
+            })
+        } else {
+            None
+        };
+
+        Ok(InfluxdbTarget {
+            client,
+            org: settings.org.clone(),
+            bucket: settings.bucket.clone(),
+            dead_letter: settings.dead_letter.clone(),
+            gzip,
+        })
+    }
+
+    /// Sends line protocol to the bucket, compressed if configured to.
+    async fn send(&self, body: Vec<u8>) -> Result<()> {
+        let gzip = match &self.gzip {
+            Some(gzip) => gzip,
+            None => {
+                return Ok(self
+                    .client
+                    .write_line_protocol(&self.org, &self.bucket, body)
+                    .await?)
+            }
+        };
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        gzip.http
+            .post(&gzip.url)
+            .query(&[
+                ("org", self.org.as_str()),
+                ("bucket", self.bucket.as_str()),
+                ("precision", "ns"),
+            ])
+            .header(header::AUTHORIZATION, format!("Token {}", gzip.token))
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(encoder.finish()?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn write(&self, points: Vec<DataPoint>) -> Result<()> {
+        let body = line_protocol(&points)?;
+
+        match self.send(body.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) => match &self.dead_letter {
+                Some(dir) => {
+                    let path = save_dead_letter(dir, &body)
+                        .with_context(|| format!("Couldn't save failed write ({})", e))?;
+                    eprintln!(
+                        "Couldn't write to InfluxDB ({}), saved {} points to {:?} to replay later",
+                        e,
+                        points.len(),
+                        path
+                    );
+                    Ok(())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Writes every dead letter in `dir` to InfluxDB, removing each once it's written.
+    async fn replay(&self, dir: &Path) -> Result<()> {
+        for path in dead_letters(dir)? {
+            let body = fs::read(&path).with_context(|| format!("Couldn't read {:?}", path))?;
+            self.send(body)
+                .await
+                .with_context(|| format!("Couldn't replay {:?}", path))?;
+            fs::remove_file(&path)?;
+            eprintln!("Replayed {:?}", path);
+        }
+        Ok(())
+    }
+}
+
+/// A QuestDB server's InfluxDB line protocol port, which takes points over plain TCP.
+pub struct QuestdbTarget {
+    address: String,
+    stream: Option<TcpStream>,
+    write_timeout: Option<Duration>,
+    dead_letter: Option<PathBuf>,
+}
+
+impl QuestdbTarget {
+    pub fn new(address: &str, settings: &InfluxdbSettings) -> Self {
+        QuestdbTarget {
+            address: address.to_string(),
+            stream: None,
+            write_timeout: settings.write_timeout.map(Duration::from_secs),
+            dead_letter: settings.dead_letter.clone(),
+        }
+    }
+
+    pub async fn write(&mut self, points: Vec<DataPoint>) -> Result<()> {
+        let body = line_protocol(&points)?;
+
+        let result = match self.write_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.send(&body))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Timed out after {:?}", timeout))),
+            None => self.send(&body).await,
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Whatever went wrong, the connection can't be trusted to be in a good state.
+                self.stream = None;
+                match &self.dead_letter {
+                    Some(dir) => {
+                        let path = save_dead_letter(dir, &body)
+                            .with_context(|| format!("Couldn't save failed write ({})", e))?;
+                        eprintln!(
+                            "Couldn't write to QuestDB ({}), saved {} points to {:?} to replay later",
+                            e,
+                            points.len(),
+                            path
+                        );
+                        Ok(())
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, body: &[u8]) -> Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            stream => stream.insert(
+                TcpStream::connect(&self.address)
+                    .await
+                    .with_context(|| format!("Couldn't connect to QuestDB at {}", self.address))?,
+            ),
+        };
+        stream.write_all(body).await?;
+        Ok(())
+    }
+
+    /// Writes every dead letter in `dir` to QuestDB, removing each once it's written.
+    async fn replay(&mut self, dir: &Path) -> Result<()> {
+        for path in dead_letters(dir)? {
+            let body = fs::read(&path).with_context(|| format!("Couldn't read {:?}", path))?;
+            self.send(&body)
+                .await
+                .with_context(|| format!("Couldn't replay {:?}", path))?;
+            fs::remove_file(&path)?;
+            eprintln!("Replayed {:?}", path);
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        if let Some(mut stream) = self.stream {
+            stream.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// A client for talking to InfluxDB, trusting `cacert` and identifying with `clientcert` if
+/// they're set.
+pub fn influxdb_client_builder(settings: &InfluxdbSettings) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::ClientBuilder::new();
+    if let Some(path) = &settings.ca_cert {
+        let pem =
+            fs::read_to_string(path).with_context(|| format!("Couldn't read cacert {:?}", path))?;
+        // Certificate::from_pem only takes the first of a bundle, so each is added on its own.
+        let certs = pem
+            .split_inclusive(PEM_CERT_END)
+            .filter(|block| block.contains(PEM_CERT_END))
+            .map(|block| reqwest::Certificate::from_pem(block.as_bytes()))
+            .collect::<reqwest::Result<Vec<_>>>()
+            .with_context(|| format!("Couldn't parse cacert {:?} as PEM", path))?;
+        if certs.is_empty() {
+            return Err(anyhow!("cacert {:?} has no PEM certificates in it", path));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let (Some(cert_path), Some(key_path)) = (&settings.client_cert, &settings.client_key) {
+        let cert = fs::read(cert_path)
+            .with_context(|| format!("Couldn't read clientcert {:?}", cert_path))?;
+        let key = fs::read(key_path)
+            .with_context(|| format!("Couldn't read clientkey {:?}", key_path))?;
+        let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key).with_context(|| {
+            format!(
+                "Couldn't use clientcert {:?} with clientkey {:?}, the key must be PKCS#8 PEM",
+                cert_path, key_path
+            )
+        })?;
+        builder = builder.identity(identity);
+    }
+    Ok(builder)
+}
+
+/// Waits until InfluxDB's health endpoint says it's ready, for when it's started at the same
+/// time as us, such as by docker-compose. Gives up after `timeout`.
+pub async fn wait_for_influxdb(settings: &InfluxdbSettings, timeout: Duration) -> Result<()> {
+    let url = format!("{}/health", settings.host.trim_end_matches('/'));
+    let http = influxdb_client_builder(settings)?
+        .timeout(READY_POLL_INTERVAL)
+        .build()
+        .context("Couldn't create InfluxDB client")?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let problem = match http.get(&url).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("it answered {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "InfluxDB at {} still wasn't ready after {}s: {}",
+                settings.host,
+                timeout.as_secs(),
+                problem
+            ));
+        }
+        eprintln!("Waiting for InfluxDB to be ready: {}", problem);
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+/// Asks InfluxDB who the configured token belongs to, to check it without writing anything.
+pub async fn verify_influxdb_token(settings: &InfluxdbSettings) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Me {
+        name: String,
+    }
+
+    let response = influxdb_client_builder(settings)?
+        .build()
+        .context("Couldn't create InfluxDB client")?
+        .get(format!("{}/api/v2/me", settings.host.trim_end_matches('/')))
+        .header(
+            header::AUTHORIZATION,
+            format!("Token {}", settings.token.secret()),
+        )
+        .send()
+        .await
+        .with_context(|| format!("Couldn't reach InfluxDB at {}", settings.host))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "InfluxDB answered {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        ));
+    }
+    Ok(response.json::<Me>().await?.name)
+}
+
+fn line_protocol(points: &[DataPoint]) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    for point in points {
+        point.write_data_point_to(&mut body)?;
+    }
+    Ok(body)
+}
+
+/// Series keys (measurement, tags and timestamp) of `points` that are already in `seen`, which
+/// they're then added to. InfluxDB quietly keeps only the last of those, so they usually mean
+/// a mistake.
+pub fn duplicate_series(seen: &mut HashSet<String>, points: &[DataPoint]) -> Result<Vec<String>> {
+    let mut duplicates = vec![];
+    for point in points {
+        let mut line = vec![];
+        point.write_data_point_to(&mut line)?;
+        let key = series_key(String::from_utf8_lossy(&line).trim_end());
+        if !seen.insert(key.clone()) {
+            duplicates.push(key);
+        }
+    }
+    Ok(duplicates)
+}
+
+/// A line of line protocol without its fields.
+fn series_key(line: &str) -> String {
+    // The measurement and tags end at the first space that isn't escaped.
+    let mut escaped = false;
+    let end = line
+        .char_indices()
+        .find(|(_, c)| {
+            let found = *c == ' ' && !escaped;
+            escaped = *c == '\\' && !escaped;
+            found
+        })
+        .map_or(line.len(), |(index, _)| index);
+    let timestamp = line.rsplit(' ').next().unwrap_or_default();
+    format!("{} {}", &line[..end], timestamp)
+}
+
+/// The dead letters saved in `dir`, oldest first.
+fn dead_letters(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)
+        .with_context(|| format!("Couldn't read dead letter directory {:?}", dir))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "lp"));
+    // File names are timestamps.
+    paths.sort();
+    Ok(paths)
+}
+
+fn save_dead_letter(dir: &Path, body: &[u8]) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}.lp",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+    ));
+    fs::write(&path, body)?;
+    Ok(path)
+}
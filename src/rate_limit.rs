@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// A token bucket that every request to battle.net draws from, so we stay under
+/// Blizzard's rate limits rather than waiting to be told off with a 429.
+///
+/// An hourly budget can be set as well. That one isn't a bucket: a bucket that starts full
+/// allows its capacity again on top of what it refills, so up to twice the budget could go
+/// out in the first hour. Instead every request in the last hour is remembered, and no more
+/// are made while there are already `per_hour` of them, which holds for any hour-long window.
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: Option<f64>,
+    /// The most tokens that can build up while idle, i.e. the largest burst allowed.
+    capacity: f64,
+    per_hour: Option<usize>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When each request in the last hour was made, oldest first. Only kept with `per_hour`.
+    recent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: Option<f64>, per_hour: Option<usize>) -> Self {
+        let capacity = per_second.map_or(1.0, |rate| rate.max(1.0));
+        RateLimiter {
+            rate: per_second,
+            capacity,
+            per_hour,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+                recent: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Waits until both budgets allow another request, and takes it from them.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                let now = Instant::now();
+                let mut wait = Duration::ZERO;
+
+                if let Some(rate) = self.rate {
+                    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                    bucket.tokens = (bucket.tokens + elapsed * rate).min(self.capacity);
+                    bucket.updated = now;
+                    if bucket.tokens < 1.0 {
+                        wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+                    }
+                }
+                if let Some(per_hour) = self.per_hour {
+                    while let Some(made) = bucket.recent.front() {
+                        if now.duration_since(*made) < HOUR {
+                            break;
+                        }
+                        bucket.recent.pop_front();
+                    }
+                    if bucket.recent.len() >= per_hour {
+                        wait = wait.max(HOUR - now.duration_since(bucket.recent[0]));
+                    }
+                }
+
+                if wait.is_zero() {
+                    if self.rate.is_some() {
+                        bucket.tokens -= 1.0;
+                    }
+                    if self.per_hour.is_some() {
+                        bucket.recent.push_back(now);
+                    }
+                    return;
+                }
+                wait
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// How many of `times`, which are sorted, fall in the `window` starting at each of them.
+    fn busiest(times: &[Instant], window: Duration) -> usize {
+        times
+            .iter()
+            .map(|start| {
+                times
+                    .iter()
+                    .filter(|time| **time >= *start && **time < *start + window)
+                    .count()
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_requests_stay_under_both_budgets() {
+        let limiter = Arc::new(RateLimiter::new(Some(5.0), Some(60)));
+        let tasks = (0..200)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire().await;
+                    Instant::now()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut times = vec![];
+        for task in tasks {
+            times.push(task.await.unwrap());
+        }
+        times.sort();
+
+        assert_eq!(times.len(), 200);
+        // A full bucket's burst of 5, plus the 5 that refill within the second.
+        assert!(busiest(&times, Duration::from_secs(1)) <= 10);
+        assert_eq!(busiest(&times, HOUR), 60);
+    }
+}
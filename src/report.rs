@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// A machine readable summary of an update, for wrapper scripts and monitoring to read
+/// instead of the log.
+#[derive(Serialize, Debug, Default)]
+pub struct RunReport {
+    pub auction_houses: Vec<AuctionHouseReport>,
+}
+
+impl RunReport {
+    /// Writes the report as JSON to `path`, or to stdout if it's `-`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if path == Path::new("-") {
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, self)?;
+            stdout.write_all(b"\n")?;
+        } else {
+            let file = File::create(path).with_context(|| format!("Couldn't create {:?}", path))?;
+            serde_json::to_writer_pretty(file, self)?;
+        }
+        Ok(())
+    }
+}
+
+/// What came of one auction house during an update.
+#[derive(Serialize, Debug)]
+pub struct AuctionHouseReport {
+    pub realm_id: i64,
+    pub ah_id: i64,
+    pub status: Status,
+    /// Auctions in the snapshot, not counting duplicates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auctions: Option<usize>,
+    /// Items written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<usize>,
+    /// When battle.net took the snapshot, or else when it was fetched, in nanoseconds since
+    /// the unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    pub duration_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuctionHouseReport {
+    pub fn new(realm_id: i64, ah_id: i64, status: Status, started: Instant) -> Self {
+        AuctionHouseReport {
+            realm_id,
+            ah_id,
+            status,
+            auctions: None,
+            items: None,
+            timestamp: None,
+            duration_seconds: started.elapsed().as_secs_f64(),
+            error: None,
+        }
+    }
+
+    pub fn failed(realm_id: i64, ah_id: i64, started: Instant, error: &anyhow::Error) -> Self {
+        AuctionHouseReport {
+            error: Some(format!("{:#}", error)),
+            ..AuctionHouseReport::new(realm_id, ah_id, Status::Failed, started)
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Fetched and written.
+    Updated,
+    /// Nothing new since last time, so left alone.
+    Unchanged,
+    /// Not fetched, such as because its realm was offline.
+    Skipped,
+    Failed,
+}
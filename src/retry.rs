@@ -0,0 +1,99 @@
+use std::future::Future;
+use std::time::Duration;
+
+/// How many times to try something that can fail transiently, and how long to wait between
+/// attempts. The wait doubles after each failure, up to `max`.
+pub struct Backoff {
+    pub retries: u32,
+    pub initial: Duration,
+    pub max: Option<Duration>,
+}
+
+impl Backoff {
+    /// Runs `attempt` until it succeeds, it fails in a way `is_transient` says won't go away
+    /// on its own, or we run out of retries.
+    pub async fn retry<T, E, F, Fut>(
+        &self,
+        is_transient: impl Fn(&E) -> bool,
+        mut attempt: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut delays = self.delays();
+        loop {
+            match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(e) if is_transient(&e) => match delays.next() {
+                    Some(delay) => {
+                        eprintln!("{}, retrying in {:?}", e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => return Err(e),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// How long to wait before each retry in turn.
+    fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        std::iter::successors(Some(self.cap(self.initial)), |delay| {
+            Some(self.cap(delay.saturating_mul(2)))
+        })
+        .take(self.retries as usize)
+    }
+
+    fn cap(&self, delay: Duration) -> Duration {
+        self.max.map_or(delay, |max| delay.min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let backoff = Backoff {
+            retries: 100,
+            initial: Duration::from_secs(1),
+            max: Some(Duration::from_secs(60)),
+        };
+        let delays = backoff.delays().collect::<Vec<_>>();
+        assert_eq!(delays.len(), 100);
+        assert_eq!(
+            delays[..7],
+            [1, 2, 4, 8, 16, 32, 60].map(Duration::from_secs)
+        );
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn delays_respect_the_cap_when_doubling_saturates() {
+        let max = Duration::from_secs(u64::MAX);
+        let backoff = Backoff {
+            retries: 10,
+            initial: Duration::from_secs(u64::MAX / 4),
+            max: Some(max),
+        };
+        let delays = backoff.delays().collect::<Vec<_>>();
+        assert_eq!(delays.len(), 10);
+        assert!(delays.iter().all(|delay| *delay <= max));
+        assert_eq!(delays[9], max);
+    }
+
+    #[test]
+    fn initial_delay_is_capped_too() {
+        let backoff = Backoff {
+            retries: 3,
+            initial: Duration::from_secs(120),
+            max: Some(Duration::from_secs(60)),
+        };
+        assert!(backoff
+            .delays()
+            .all(|delay| delay == Duration::from_secs(60)));
+    }
+}